@@ -9,11 +9,16 @@
 //! 3. Deploy-time validate AST against a dictionary snapshot + function registry.
 //! 4. Store DSL + AST JSON + dependencies.
 //! 5. Runtime evaluates AST against a small context map (deterministic, no UNKNOWN).
+//!
+//! `to_dsl` goes the other way, rendering a stored AST back into canonical DSL text.
+
+use std::collections::HashSet;
 
-use pest::Parser;
+use pest::error::LineColLocation;
+use pest::{Parser, Position};
 use pest_derive::Parser;
 use thiserror::Error;
-use tiimu_expr_ast::{CompareOp, ContainsOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp, MembershipOp};
+use tiimu_expr_ast::{walk_expr, ArithOp, CompareOp, ContainsOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp, MembershipOp, Visitor};
 
 #[derive(Parser)]
 #[grammar = "expr.pest"]
@@ -21,40 +26,187 @@ struct ExprParser;
 
 #[derive(Debug, Error)]
 pub enum DslError {
-    #[error("parse error: {0}")]
-    Parse(String),
+    /// Invalid DSL text.
+    ///
+    /// `line`/`col` are 1-based and point at the offending position; `snippet` is the
+    /// full source line containing it, so a UI can underline the token. Displays in pest's
+    /// multi-line format (location, source line, caret, message).
+    #[error("parse error: {}", render_parse_error(message, *line, *col, snippet))]
+    Parse { message: String, line: usize, col: usize, snippet: String },
+    /// A call to a function outside the known set (see `parse_expression_with_functions`).
+    #[error("unknown function: {0}")]
+    UnknownFunction(String),
+}
+
+/// Functions every deployment understands, accepted by `parse_expression_with_functions`
+/// regardless of the caller's set: the `exists` special form plus everything
+/// `tiimu_expr_eval::FunctionRegistry::with_builtins` registers (which checks it against this
+/// list in debug builds). `datetime` is only registered with the evaluator's `chrono` feature.
+pub const BUILTIN_FUNCTIONS: &[&str] = &[
+    "exists", "len", "abs", "floor", "ceil", "round", "min", "max", "lower", "upper", "trim",
+    "intersect", "union", "difference", "overlaps", "subset", "superset", "contains_any",
+    "contains_all", "is_integer", "approx_eq", "datetime", "coalesce",
+];
+
+impl DslError {
+    fn at(pos: pest::Position, message: impl Into<String>) -> Self {
+        let (line, col) = pos.line_col();
+        let snippet = pos.line_of().trim_end_matches(['\r', '\n']).to_string();
+        DslError::Parse { message: message.into(), line, col, snippet }
+    }
+
+    fn from_pest(e: pest::error::Error<Rule>) -> Self {
+        let (line, col) = match e.line_col {
+            LineColLocation::Pos(lc) | LineColLocation::Span(lc, _) => lc,
+        };
+        DslError::Parse { message: e.variant.message().into_owned(), line, col, snippet: e.line().to_string() }
+    }
+}
+
+/// Same text as pest's `Display` for an error at a single position.
+fn render_parse_error(message: &str, line: usize, col: usize, snippet: &str) -> String {
+    let spacing = " ".repeat(line.to_string().len());
+    let indent: String = snippet.chars().take(col.saturating_sub(1)).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    format!(
+        "{s}--> {line}:{col}\n{s} |\n{line} | {snippet}\n{s} | {indent}^---\n{s} |\n{s} = {message}",
+        s = spacing,
+    )
+}
+
+/// Parser settings for `parse_expression_with_options`.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Allow a field reference on the right-hand side of a predicate (`a == b`, `x in y`,
+    /// `x between lo and 10`), comparisons with fields on both sides (`x == (y)`,
+    /// `len(a) == b + 1`) and fields inside lists (`x in [a, "b"]`). Default `true`.
+    pub allow_field_rhs: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self { Self { allow_field_rhs: true } }
 }
 
 /// Parses DSL text into an AST (`tiimu_expr_ast::Expr`).
 ///
-/// Returns a structured parse error string if the input is invalid.
+/// Returns `DslError::Parse` with the line/column of the offending token if the input is invalid.
 pub fn parse_expression(input: &str) -> Result<Expr, DslError> {
-    let mut pairs = ExprParser::parse(Rule::expression, input).map_err(|e| DslError::Parse(e.to_string()))?;
-    let pair = pairs.next().ok_or_else(|| DslError::Parse("empty expression".into()))?;
-    build_expr(pair)
+    parse_expression_with_options(input, &ParseOptions::default())
+}
+
+/// Like `parse_expression`, with deployment-specific restrictions from `opts`.
+///
+/// With `allow_field_rhs: false`, comparing a field against another field (see
+/// `ParseOptions::allow_field_rhs`) fails with
+/// `DslError::Parse("field-to-field comparison not allowed")` pointing at that field.
+pub fn parse_expression_with_options(input: &str, opts: &ParseOptions) -> Result<Expr, DslError> {
+    let empty = || DslError::at(Position::from_start(input), "empty expression");
+    let mut pairs = ExprParser::parse(Rule::dsl, input).map_err(DslError::from_pest)?;
+    let dsl = pairs.next().ok_or_else(empty)?;
+    let pair = dsl.clone().into_inner().next().ok_or_else(empty)?;
+    let expr = build_expr(pair)?;
+    if !opts.allow_field_rhs { reject_field_rhs(&dsl, &expr)?; }
+    Ok(expr)
+}
+
+/// Fails on the first field the expression compares against another field: a field RHS of a
+/// predicate (values, bounds, membership targets), the right side of a comparison whose left
+/// side also reads a field (`x == (y)`, `len(a) == b`, chain links like `1 < x < y`), and any
+/// field inside a list literal, including list arguments of calls.
+///
+/// The error points at the first occurrence of the offending field in `dsl` that is not the
+/// left-hand side of a predicate or comparison (falling back to its first occurrence).
+fn reject_field_rhs(dsl: &pest::iterators::Pair<Rule>, expr: &Expr) -> Result<(), DslError> {
+    let mut check = FieldRhsCheck(None);
+    walk_expr(expr, &mut check);
+    let Some(field) = check.0 else { return Ok(()) };
+    let pairs = || dsl.clone().into_inner().flatten();
+    let subjects: HashSet<usize> = pairs()
+        .filter(|p| p.as_rule() == Rule::predicate || (p.as_rule() == Rule::comparison && p.clone().into_inner().nth(1).is_some()))
+        .filter_map(|p| p.into_inner().flatten().find(|f| f.as_rule() == Rule::field_ref))
+        .map(|f| f.as_span().start())
+        .collect();
+    let occurrences: Vec<_> = pairs()
+        .filter(|p| p.as_rule() == Rule::field_ref && parse_field_ref(p).is_ok_and(|f| f == field))
+        .collect();
+    let pos = occurrences.iter().find(|p| !subjects.contains(&p.as_span().start())).or(occurrences.first())
+        .map_or_else(|| dsl.as_span().start_pos(), |p| p.as_span().start_pos());
+    Err(DslError::at(pos, "field-to-field comparison not allowed"))
+}
+
+/// Records the first field `reject_field_rhs` disallows.
+struct FieldRhsCheck(Option<FieldRef>);
+
+impl Visitor for FieldRhsCheck {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if self.0.is_some() { return; }
+        self.0 = match expr {
+            Expr::Compare { value: LiteralOrField::Field(f), .. }
+            | Expr::Membership { list: LiteralOrField::Field(f), .. }
+            | Expr::Contains { value: LiteralOrField::Field(f), .. } => Some(f.clone()),
+            Expr::Between { low, high, .. } => [low, high].into_iter().find_map(|v| match v {
+                LiteralOrField::Field(f) => Some(f.clone()),
+                LiteralOrField::Lit(_) => None,
+            }),
+            Expr::CompareExpr { lhs, rhs, .. } if first_field(lhs).is_some() => first_field(rhs),
+            _ => None,
+        };
+    }
+
+    fn visit_literal(&mut self, literal: &Literal) {
+        if self.0.is_some() { return; }
+        if let Literal::List(items) = literal {
+            self.0 = items.iter().find_map(|item| match item {
+                LiteralOrField::Field(f) => Some(f.clone()),
+                LiteralOrField::Lit(_) => None,
+            });
+        }
+    }
+}
+
+/// The first field `expr` reads, in source order.
+fn first_field(expr: &Expr) -> Option<FieldRef> {
+    struct First(Option<FieldRef>);
+    impl Visitor for First {
+        fn visit_field(&mut self, field: &FieldRef) { self.0.get_or_insert_with(|| field.clone()); }
+    }
+    let mut first = First(None);
+    walk_expr(expr, &mut first);
+    first.0
+}
+
+/// Like `parse_expression`, but also rejects calls to functions that are neither in `known`
+/// nor `BUILTIN_FUNCTIONS` with `DslError::UnknownFunction`.
+///
+/// When several are unknown, the alphabetically first is reported.
+pub fn parse_expression_with_functions(input: &str, known: &HashSet<String>) -> Result<Expr, DslError> {
+    let expr = parse_expression(input)?;
+    let mut unknown: Vec<String> = tiimu_expr_ast::extract_dependencies(&expr).functions.into_iter()
+        .filter(|f| !known.contains(f) && !BUILTIN_FUNCTIONS.contains(&f.as_str()))
+        .collect();
+    unknown.sort();
+    match unknown.into_iter().next() {
+        Some(name) => Err(DslError::UnknownFunction(name)),
+        None => Ok(expr),
+    }
 }
 
 fn build_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
     match pair.as_rule() {
-        Rule::expression => build_expr(pair.into_inner().next().unwrap()),
-        Rule::or_expr => {
+        Rule::expression => {
             let mut inner = pair.into_inner();
-            let mut expr = build_expr(inner.next().unwrap())?;
-            while let Some(_) = inner.next() {
-                let rhs = build_expr(inner.next().unwrap())?;
-                expr = Expr::Logical { op: LogicalOp::Or, lhs: Box::new(expr), rhs: Box::new(rhs) };
+            let cond = build_expr(inner.next().unwrap())?;
+            match (inner.next(), inner.next()) {
+                (Some(then), Some(els)) => Ok(Expr::If {
+                    cond: Box::new(cond),
+                    then: Box::new(build_expr(then)?),
+                    els: Box::new(build_expr(els)?),
+                }),
+                _ => Ok(cond),
             }
-            Ok(expr)
-        }
-        Rule::and_expr => {
-            let mut inner = pair.into_inner();
-            let mut expr = build_expr(inner.next().unwrap())?;
-            while let Some(_) = inner.next() {
-                let rhs = build_expr(inner.next().unwrap())?;
-                expr = Expr::Logical { op: LogicalOp::And, lhs: Box::new(expr), rhs: Box::new(rhs) };
-            }
-            Ok(expr)
         }
+        Rule::or_expr => build_logical(pair, LogicalOp::Or),
+        Rule::xor_expr => build_logical(pair, LogicalOp::Xor),
+        Rule::and_expr => build_logical(pair, LogicalOp::And),
         Rule::unary_expr => {
             let s = pair.as_str().trim();
             let mut inner = pair.into_inner();
@@ -62,86 +214,319 @@ fn build_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
             let e = build_expr(prim)?;
             if s.starts_with('!') { Ok(Expr::Not(Box::new(e))) } else { Ok(e) }
         }
-        Rule::primary => build_expr(pair.into_inner().next().unwrap()),
+        // A parenthesized `operand` holds a whole `expression`, so grouping is kept by the tree
+        // shape alone: `!(a || b)` is `Not(Or)`, `!a || b` is `Or(Not, b)`.
+        Rule::primary | Rule::operand => build_expr(pair.into_inner().next().unwrap()),
         Rule::predicate => build_predicate(pair),
+        Rule::comparison => build_comparison(pair),
+        Rule::sum | Rule::product => build_arithmetic(pair),
         Rule::function_call => build_call(pair),
         Rule::literal => Ok(Expr::Literal(build_literal(pair.into_inner().next().unwrap())?)),
-        Rule::field_expr => Ok(Expr::Field(parse_field_ref(pair.as_str()))),
+        Rule::list => Ok(Expr::Literal(build_list(pair)?)),
+        Rule::field_expr => Ok(Expr::Field(parse_field_ref(&pair)?)),
         _ => Ok(Expr::Literal(Literal::Null)),
     }
 }
 
-fn parse_field_ref(s: &str) -> FieldRef {
-    FieldRef::new(s.split('.').map(|x| x.to_string()).collect())
+/// Left-folds `a && b && c` (or `||`, `^^`) into nested `Expr::Logical` nodes.
+///
+/// `&&` and `||` are anonymous in the grammar; `xor_op` is a token (so `xor` must be a whole
+/// word) and is skipped. Every other inner pair is an operand.
+fn build_logical(pair: pest::iterators::Pair<Rule>, op: LogicalOp) -> Result<Expr, DslError> {
+    let mut inner = pair.into_inner().filter(|p| p.as_rule() != Rule::xor_op);
+    let mut expr = build_expr(inner.next().unwrap())?;
+    for rhs in inner {
+        let rhs = build_expr(rhs)?;
+        expr = Expr::Logical { op: op.clone(), lhs: Box::new(expr), rhs: Box::new(rhs) };
+    }
+    Ok(expr)
+}
+
+/// Left-folds a `sum`/`product` rule (`operand (op operand)*`) into `Expr::Arithmetic`.
+fn build_arithmetic(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
+    let mut inner = pair.into_inner();
+    let mut expr = build_expr(inner.next().unwrap())?;
+    while let Some(op_pair) = inner.next() {
+        let op = match op_pair.as_str() {
+            "+" => ArithOp::Add, "-" => ArithOp::Sub,
+            "*" => ArithOp::Mul, "/" => ArithOp::Div,
+            _ => ArithOp::Mod,
+        };
+        let rhs = build_expr(inner.next().unwrap())?;
+        expr = Expr::Arithmetic { op, lhs: Box::new(expr), rhs: Box::new(rhs) };
+    }
+    Ok(expr)
+}
+
+/// Longest accepted comparison chain, in comparators (`lo < x <= hi` has two).
+const MAX_COMPARISON_CHAIN: usize = 2;
+
+/// Builds `a op b`, or desugars a chain `lo < x < hi` into `lo < x && x < hi`.
+///
+/// Chains must run in one direction (all `<`/`<=` or all `>`/`>=`); `==`/`!=` cannot be chained.
+/// Each link is built as if written out, so `x < 10` becomes `Expr::Compare`.
+fn build_comparison(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
+    let mut inner = pair.into_inner();
+    let first = build_expr(inner.next().unwrap())?;
+    let Some(op_pair) = inner.next() else { return Ok(first) };
+    let rhs_pair = inner.next().unwrap();
+    let rhs_pos = rhs_pair.as_span().start_pos();
+    let rhs = build_expr(rhs_pair)?;
+    let op = build_compare_op(op_pair.as_str());
+    if inner.peek().is_none() {
+        reject_scalar_list_compare(&op, &first, &rhs, rhs_pos)?;
+        return Ok(Expr::CompareExpr { op, lhs: Box::new(first), rhs: Box::new(rhs) });
+    }
+    let ascending = |op: &CompareOp| match op {
+        CompareOp::Lt | CompareOp::Le => Some(true),
+        CompareOp::Gt | CompareOp::Ge => Some(false),
+        CompareOp::Eq | CompareOp::Ne => None,
+    };
+    let direction = ascending(&op)
+        .ok_or_else(|| DslError::at(op_pair.as_span().start_pos(), "`==` and `!=` cannot be chained"))?;
+    let mut links = vec![chain_link(first, op, rhs.clone())];
+    let mut prev = rhs;
+    while let Some(op_pair) = inner.next() {
+        let pos = op_pair.as_span().start_pos();
+        if links.len() == MAX_COMPARISON_CHAIN {
+            return Err(DslError::at(pos, format!("comparison chains are limited to {} comparators", MAX_COMPARISON_CHAIN)));
+        }
+        let op = build_compare_op(op_pair.as_str());
+        match ascending(&op) {
+            None => return Err(DslError::at(pos, "`==` and `!=` cannot be chained")),
+            Some(d) if d != direction => return Err(DslError::at(pos, "comparison chain mixes `<` and `>` directions")),
+            Some(_) => {}
+        }
+        let next = build_expr(inner.next().unwrap())?;
+        links.push(chain_link(prev, op, next.clone()));
+        prev = next;
+    }
+    let mut links = links.into_iter();
+    let first = links.next().expect("chain has links");
+    Ok(links.fold(first, |lhs, rhs| Expr::Logical { op: LogicalOp::And, lhs: Box::new(lhs), rhs: Box::new(rhs) }))
+}
+
+/// `x != [1, 2]` where `x` cannot be a set is always a type error; point at `in` / `not in`.
+///
+/// Only left-hand sides known to be scalars here (arithmetic, scalar literals, boolean
+/// expressions) are rejected. A field or call may hold a set, and set equality with a list
+/// literal is valid (`tags == ["a", "b"]`), so those are left to the typechecker and evaluator,
+/// which give the same hint.
+fn reject_scalar_list_compare(op: &CompareOp, lhs: &Expr, rhs: &Expr, pos: Position) -> Result<(), DslError> {
+    let Expr::Literal(Literal::List(items)) = rhs else { return Ok(()) };
+    let scalar_items = items.iter().all(|i| matches!(i, LiteralOrField::Lit(l) if !matches!(l, Literal::List(_))));
+    let scalar_lhs = match lhs {
+        Expr::Literal(l) => !matches!(l, Literal::List(_)),
+        Expr::Field(_) | Expr::Call { .. } | Expr::If { .. } => false,
+        _ => true,
+    };
+    let hint = match op {
+        CompareOp::Eq => "use `in` to test membership in a list",
+        CompareOp::Ne => "use `not in` to test membership in a list",
+        _ => return Ok(()),
+    };
+    if scalar_items && scalar_lhs {
+        return Err(DslError::at(pos, format!("cannot compare a scalar with a list; {}", hint)));
+    }
+    Ok(())
+}
+
+/// One comparison of a chain, in the form the parser gives the same text on its own.
+fn chain_link(lhs: Expr, op: CompareOp, rhs: Expr) -> Expr {
+    match op {
+        CompareOp::Eq => lhs.equals(rhs), CompareOp::Ne => lhs.not_equals(rhs),
+        CompareOp::Lt => lhs.lt(rhs), CompareOp::Le => lhs.le(rhs),
+        CompareOp::Gt => lhs.gt(rhs), CompareOp::Ge => lhs.ge(rhs),
+    }
+}
+
+fn build_compare_op(s: &str) -> CompareOp {
+    match s {
+        "==" => CompareOp::Eq, "!=" => CompareOp::Ne,
+        "<" => CompareOp::Lt, "<=" => CompareOp::Le,
+        ">" => CompareOp::Gt, ">=" => CompareOp::Ge,
+        _ => CompareOp::Eq,
+    }
+}
+
+/// Splits `a.b[0].1` into `["a", "b", "0", "1"]`; `[n]` and `.n` are equivalent.
+fn parse_field_ref(pair: &pest::iterators::Pair<Rule>) -> Result<FieldRef, DslError> {
+    pair.as_str().replace('[', ".").replace(']', "").parse()
+        .map_err(|e: tiimu_expr_ast::ParseFieldRefError| DslError::at(pair.as_span().start_pos(), e.to_string()))
 }
 
 fn build_literal(pair: pest::iterators::Pair<Rule>) -> Result<Literal, DslError> {
     match pair.as_rule() {
         Rule::boolean => Ok(Literal::Bool(pair.as_str() == "true")),
-        Rule::number => Ok(Literal::Number(pair.as_str().parse().map_err(|_| DslError::Parse("invalid number".into()))?)),
-        Rule::string => {
-            let raw = pair.as_str();
-            let inner = &raw[1..raw.len()-1];
-            Ok(Literal::String(inner.replace("\\\"", "\"").replace("\\\\", "\\")))
+        Rule::number => {
+            let n: f64 = pair.as_str().parse().map_err(|_| DslError::at(pair.as_span().start_pos(), "invalid number"))?;
+            // The grammar has no `inf`/`nan` tokens, but huge literals still overflow to infinity.
+            if !n.is_finite() { return Err(DslError::at(pair.as_span().start_pos(), "number literal is not finite")); }
+            Ok(Literal::Number(n))
         }
+        Rule::duration => {
+            let text = pair.as_str();
+            let (amount, unit) = text.split_at(text.len() - 1);
+            let seconds = match unit { "s" => 1.0, "m" => 60.0, "h" => 3600.0, _ => 86400.0 };
+            let n: f64 = amount.parse().map_err(|_| DslError::at(pair.as_span().start_pos(), "invalid duration"))?;
+            if !(n * seconds).is_finite() { return Err(DslError::at(pair.as_span().start_pos(), "duration literal is not finite")); }
+            Ok(Literal::Number(n * seconds))
+        }
+        Rule::string => Ok(Literal::String(unquote(&pair)?)),
         Rule::null => Ok(Literal::Null),
         _ => Ok(Literal::Null),
     }
 }
 
+/// Strips the quotes from a `string` token and decodes its escapes.
+///
+/// `"""raw"""` is taken verbatim. `"..."` and `'...'` accept `\n \t \r \\ \" \'` and `\uXXXX`;
+/// any other escape is a parse error pointing at the offending backslash.
+fn unquote(pair: &pest::iterators::Pair<Rule>) -> Result<String, DslError> {
+    let span = pair.as_span();
+    let raw = span.as_str();
+    if let Some(inner) = raw.strip_prefix("\"\"\"").and_then(|r| r.strip_suffix("\"\"\"")) {
+        return Ok(inner.to_string());
+    }
+    let inner = &raw[1..raw.len()-1];
+    let err_at = |offset: usize, msg: String| {
+        let pos = pest::Position::new(span.get_input(), span.start() + 1 + offset).unwrap_or_else(|| span.start_pos());
+        DslError::at(pos, msg)
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' { out.push(c); continue; }
+        match chars.next().map(|(_, e)| e) {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(e @ ('\\' | '"' | '\'')) => out.push(e),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).map(|(_, h)| h).collect();
+                let decoded = if hex.len() == 4 && hex.chars().all(|h| h.is_ascii_hexdigit()) {
+                    u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+                } else { None };
+                match decoded {
+                    Some(ch) => out.push(ch),
+                    None => return Err(err_at(i, format!("invalid unicode escape \\u{}", hex))),
+                }
+            }
+            Some(e) => return Err(err_at(i, format!("invalid escape sequence \\{}", e))),
+            None => return Err(err_at(i, "dangling escape at end of string".into())),
+        }
+    }
+    Ok(out)
+}
+
 fn build_value_or_field(pair: pest::iterators::Pair<Rule>) -> Result<LiteralOrField, DslError> {
     match pair.as_rule() {
         Rule::value => build_value_or_field(pair.into_inner().next().unwrap()),
-        Rule::field_ref => Ok(LiteralOrField::Field(parse_field_ref(pair.as_str()))),
-        Rule::string | Rule::number | Rule::boolean | Rule::null => Ok(LiteralOrField::Lit(build_literal(pair)?)),
+        Rule::field_ref => Ok(LiteralOrField::Field(parse_field_ref(&pair)?)),
+        Rule::string | Rule::duration | Rule::number | Rule::boolean | Rule::null => Ok(LiteralOrField::Lit(build_literal(pair)?)),
         _ => Ok(LiteralOrField::Lit(Literal::Null)),
     }
 }
 
 fn build_predicate(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
-    let text = pair.as_str();
     let mut inner = pair.into_inner();
 
-    let field = parse_field_ref(inner.next().unwrap().as_str());
-    let rest: Vec<_> = inner.collect();
-
-    if text.contains(" contains ") {
-        let value = build_value_or_field(rest.last().unwrap().clone())?;
-        Ok(Expr::Contains { field, op: ContainsOp::Contains, value })
-    } else if text.contains("~") {
-        let regex_pair = rest.iter().find(|p| p.as_rule() == Rule::regex).unwrap();
-        let raw = regex_pair.as_str();
-        Ok(Expr::RegexMatch { field, pattern: raw[1..raw.len()-1].to_string() })
-    } else if text.contains(" in ") || text.contains(" not in ") {
-        let op = if text.contains(" not in ") { MembershipOp::NotIn } else { MembershipOp::In };
-        let target = rest.last().unwrap().clone();
-        let list = match target.as_rule() {
-            Rule::list => {
-                let mut items = vec![];
-                for p in target.into_inner() {
-                    if p.as_rule() == Rule::value {
-                        items.push(build_value_or_field(p)?);
-                    }
+    let field = parse_field_ref(&inner.next().unwrap())?;
+    // The operator token decides the predicate kind; the source text is never inspected, so
+    // operands like `"x in y"` or `/a ~ b/` cannot be mistaken for operators.
+    let op_pair = inner.next().unwrap();
+    let mut operands = inner.filter(|p| p.as_rule() != Rule::between_and);
+
+    match op_pair.as_rule() {
+        Rule::comparator => {
+            let op = build_compare_op(op_pair.as_str());
+            let value = build_value_or_field(operands.next().unwrap())?;
+            Ok(Expr::Compare { field, op, value })
+        }
+        Rule::null_check => {
+            // `is null` / `is not null` lower to the `== null` / `!= null` forms.
+            let op = if op_pair.as_str().contains("not") { CompareOp::Ne } else { CompareOp::Eq };
+            Ok(Expr::Compare { field, op, value: LiteralOrField::Lit(Literal::Null) })
+        }
+        Rule::between => {
+            let low = build_value_or_field(operands.next().unwrap())?;
+            let high = build_value_or_field(operands.next().unwrap())?;
+            Ok(Expr::Between { field, low, high, inclusive: true })
+        }
+        Rule::contains => {
+            let op = match op_pair.as_str() {
+                "starts_with" => ContainsOp::StartsWith,
+                "ends_with" => ContainsOp::EndsWith,
+                "not_contains" => ContainsOp::NotContains,
+                _ => ContainsOp::Contains,
+            };
+            let value = build_value_or_field(operands.next().unwrap())?;
+            Ok(Expr::Contains { field, op, value })
+        }
+        Rule::regexop => {
+            let pattern_pair = operands.next().unwrap();
+            let mut pattern = match pattern_pair.as_rule() {
+                Rule::regex => {
+                    let raw = pattern_pair.as_str();
+                    unescape_regex(&raw[1..raw.len()-1])
                 }
-                LiteralOrField::Lit(Literal::List(items))
+                _ => unquote(&pattern_pair)?,
+            };
+            if op_pair.as_str() == "~i" {
+                pattern.insert_str(0, "(?i)");
             }
-            Rule::field_ref => LiteralOrField::Field(parse_field_ref(target.as_str())),
-            _ => LiteralOrField::Lit(Literal::Null),
-        };
-        Ok(Expr::Membership { field, op, list })
-    } else {
-        let comp_pair = rest.iter().find(|p| p.as_rule() == Rule::comparator).unwrap();
-        let op = match comp_pair.as_str() {
-            "==" => CompareOp::Eq, "!=" => CompareOp::Ne,
-            "<" => CompareOp::Lt, "<=" => CompareOp::Le,
-            ">" => CompareOp::Gt, ">=" => CompareOp::Ge,
-            _ => CompareOp::Eq,
-        };
-        let value = build_value_or_field(rest.last().unwrap().clone())?;
-        Ok(Expr::Compare { field, op, value })
+            if let Err(e) = regex::Regex::new(&pattern) {
+                return Err(DslError::at(pattern_pair.as_span().start_pos(), format!("invalid regex: {}", e)));
+            }
+            Ok(Expr::RegexMatch { field, pattern, anchored: op_pair.as_str() == "~=" })
+        }
+        Rule::membership => {
+            let op = if op_pair.as_str().starts_with("not") { MembershipOp::NotIn } else { MembershipOp::In };
+            let target = operands.next().unwrap();
+            let list = match target.as_rule() {
+                Rule::list => LiteralOrField::Lit(build_list(target)?),
+                Rule::range => LiteralOrField::Lit(build_range(&target)?),
+                _ => LiteralOrField::Field(parse_field_ref(&target)?),
+            };
+            Ok(Expr::Membership { field, op, list })
+        }
+        r => unreachable!("unexpected predicate operator {:?}", r),
     }
 }
 
+/// Body of a `/.../` literal: `\/` becomes `/`, every other escape is kept for the regex engine.
+fn unescape_regex(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' { out.push(c); continue; }
+        match chars.next() {
+            Some('/') => out.push('/'),
+            Some(next) => { out.push('\\'); out.push(next); }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn build_list(pair: pest::iterators::Pair<Rule>) -> Result<Literal, DslError> {
+    let items = pair.into_inner()
+        .filter(|p| p.as_rule() == Rule::value)
+        .map(build_value_or_field)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Literal::List(items))
+}
+
+fn build_range(pair: &pest::iterators::Pair<Rule>) -> Result<Literal, DslError> {
+    let text = pair.as_str();
+    let (start, end, inclusive) = match text.split_once("..=") {
+        Some((s, e)) => (s, e, true),
+        None => { let (s, e) = text.split_once("..").expect("range token has .."); (s, e, false) }
+    };
+    let bound = |b: &str| b.parse::<i64>().map_err(|_| DslError::at(pair.as_span().start_pos(), format!("range bound out of range: {}", b)));
+    Ok(Literal::Range { start: bound(start)?, end: bound(end)?, inclusive })
+}
+
 fn build_call(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
     let mut inner = pair.into_inner();
     let name = inner.next().unwrap().as_str().to_string();
@@ -150,3 +535,201 @@ fn build_call(pair: pest::iterators::Pair<Rule>) -> Result<Expr, DslError> {
         .collect::<Result<Vec<_>,_>>()?;
     Ok(Expr::Call { name, args })
 }
+
+/// Renders an AST back into canonical DSL text.
+///
+/// Parentheses are inserted only where precedence or associativity requires them, so
+/// `parse_expression(&to_dsl(&e))` yields `e` again for every AST the parser can produce.
+pub fn to_dsl(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr);
+    out
+}
+
+// Binding strength used by `to_dsl`: higher binds tighter.
+const PREC_IF: u8 = 0;
+const PREC_OR: u8 = 1;
+const PREC_XOR: u8 = 2;
+const PREC_AND: u8 = 3;
+const PREC_NOT: u8 = 4;
+const PREC_PREDICATE: u8 = 5;
+const PREC_SUM: u8 = 6;
+const PREC_PRODUCT: u8 = 7;
+const PREC_ATOM: u8 = 8;
+
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::If { .. } => PREC_IF,
+        Expr::Logical { op: LogicalOp::Or, .. } => PREC_OR,
+        Expr::Logical { op: LogicalOp::Xor, .. } => PREC_XOR,
+        Expr::Logical { op: LogicalOp::And, .. } => PREC_AND,
+        Expr::Not(_) => PREC_NOT,
+        // There is no exclusive `between` syntax; it prints as `field > low && field < high`.
+        Expr::Between { inclusive: false, .. } => PREC_AND,
+        Expr::Compare { .. } | Expr::CompareExpr { .. } | Expr::Membership { .. } | Expr::Between { .. }
+        | Expr::Contains { .. } | Expr::RegexMatch { .. } => PREC_PREDICATE,
+        Expr::Arithmetic { op: ArithOp::Add | ArithOp::Sub, .. } => PREC_SUM,
+        Expr::Arithmetic { .. } => PREC_PRODUCT,
+        Expr::Call { .. } | Expr::Literal(_) | Expr::Field(_) => PREC_ATOM,
+    }
+}
+
+fn write_operand(out: &mut String, expr: &Expr, parens: bool) {
+    if parens { out.push('('); }
+    write_expr(out, expr);
+    if parens { out.push(')'); }
+}
+
+fn write_expr(out: &mut String, expr: &Expr) {
+    match expr {
+        Expr::If { cond, then, els } => {
+            write_operand(out, cond, matches!(**cond, Expr::If { .. }));
+            out.push_str(" ? ");
+            write_expr(out, then);
+            out.push_str(" : ");
+            write_expr(out, els);
+        }
+        Expr::Not(e) => {
+            out.push('!');
+            write_operand(out, e, precedence(e) < PREC_ATOM);
+        }
+        Expr::Logical { op, lhs, rhs } => {
+            let (prec, token) = match op {
+                LogicalOp::Or => (PREC_OR, " || "), LogicalOp::Xor => (PREC_XOR, " ^^ "), LogicalOp::And => (PREC_AND, " && "),
+            };
+            write_operand(out, lhs, precedence(lhs) < prec);
+            out.push_str(token);
+            write_operand(out, rhs, precedence(rhs) <= prec);
+        }
+        Expr::Arithmetic { op, lhs, rhs } => {
+            let (prec, token) = match op {
+                ArithOp::Add => (PREC_SUM, " + "), ArithOp::Sub => (PREC_SUM, " - "),
+                ArithOp::Mul => (PREC_PRODUCT, " * "), ArithOp::Div => (PREC_PRODUCT, " / "),
+                ArithOp::Mod => (PREC_PRODUCT, " % "),
+            };
+            write_operand(out, lhs, precedence(lhs) < prec);
+            out.push_str(token);
+            write_operand(out, rhs, precedence(rhs) <= prec);
+        }
+        Expr::Compare { field, op, value } => {
+            out.push_str(&field.as_dotted());
+            out.push_str(compare_token(op));
+            write_value(out, value);
+        }
+        Expr::CompareExpr { op, lhs, rhs } => {
+            // A bare field on the left followed by a plain value would re-parse as `Compare`.
+            let value_like = matches!(**rhs, Expr::Field(_) | Expr::Literal(_));
+            let lhs_parens = precedence(lhs) < PREC_SUM || (matches!(**lhs, Expr::Field(_)) && value_like);
+            write_operand(out, lhs, lhs_parens);
+            out.push_str(compare_token(op));
+            write_operand(out, rhs, precedence(rhs) < PREC_SUM);
+        }
+        Expr::Membership { field, op, list } => {
+            out.push_str(&field.as_dotted());
+            out.push_str(match op { MembershipOp::In => " in ", MembershipOp::NotIn => " not in " });
+            write_value(out, list);
+        }
+        Expr::Between { field, low, high, inclusive } => {
+            let field = field.as_dotted();
+            out.push_str(&field);
+            if *inclusive {
+                out.push_str(" between ");
+                write_value(out, low);
+                out.push_str(" and ");
+            } else {
+                out.push_str(" > ");
+                write_value(out, low);
+                out.push_str(" && ");
+                out.push_str(&field);
+                out.push_str(" < ");
+            }
+            write_value(out, high);
+        }
+        Expr::Contains { field, op, value } => {
+            out.push_str(&field.as_dotted());
+            out.push_str(match op {
+                ContainsOp::Contains => " contains ",
+                ContainsOp::NotContains => " not_contains ",
+                ContainsOp::StartsWith => " starts_with ",
+                ContainsOp::EndsWith => " ends_with ",
+            });
+            write_value(out, value);
+        }
+        Expr::RegexMatch { field, pattern, anchored } => {
+            out.push_str(&field.as_dotted());
+            out.push_str(if *anchored { " ~= " } else { " ~ " });
+            write_regex(out, pattern);
+        }
+        Expr::Call { name, args } => {
+            out.push_str(name);
+            out.push('(');
+            for (i, a) in args.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                write_expr(out, a);
+            }
+            out.push(')');
+        }
+        Expr::Literal(l) => write_literal(out, l),
+        Expr::Field(fr) => out.push_str(&fr.as_dotted()),
+    }
+}
+
+fn compare_token(op: &CompareOp) -> &'static str {
+    match op {
+        CompareOp::Eq => " == ", CompareOp::Ne => " != ",
+        CompareOp::Lt => " < ", CompareOp::Le => " <= ",
+        CompareOp::Gt => " > ", CompareOp::Ge => " >= ",
+    }
+}
+
+fn write_value(out: &mut String, v: &LiteralOrField) {
+    match v {
+        LiteralOrField::Lit(l) => write_literal(out, l),
+        LiteralOrField::Field(fr) => out.push_str(&fr.as_dotted()),
+    }
+}
+
+fn write_literal(out: &mut String, l: &Literal) {
+    match l {
+        Literal::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Literal::Number(n) => out.push_str(&n.to_string()),
+        Literal::String(s) => {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\t' => out.push_str("\\t"),
+                    '\r' => out.push_str("\\r"),
+                    c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        Literal::Null => out.push_str("null"),
+        Literal::Regex(p) => write_regex(out, p),
+        Literal::Range { start, end, inclusive } => out.push_str(&format!("{}{}{}", start, if *inclusive { "..=" } else { ".." }, end)),
+        Literal::List(items) => {
+            out.push('[');
+            for (i, it) in items.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                write_value(out, it);
+            }
+            out.push(']');
+        }
+    }
+}
+
+/// Writes `/pattern/`, escaping any `/` not already escaped so the literal stays delimited.
+fn write_regex(out: &mut String, pattern: &str) {
+    out.push('/');
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if c == '/' && !escaped { out.push('\\'); }
+        escaped = c == '\\' && !escaped;
+        out.push(c);
+    }
+    out.push('/');
+}
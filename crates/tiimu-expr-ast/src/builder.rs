@@ -0,0 +1,164 @@
+//! Fluent constructors for `Expr`, for codegen tools that would otherwise nest `Box::new` by hand.
+//!
+//! The builders produce the same trees as the DSL parser, e.g.
+//! `field("customer.is_known").equals(lit(true)).and(field("x").gt(lit(2.0)))` equals
+//! `parse_expression("customer.is_known == true && x > 2")`.
+
+use crate::{CompareOp, ContainsOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp, MembershipOp};
+
+/// A field reference such as `customer.tags`; index segments are written dotted (`tags.0`).
+///
+/// # Panics
+/// If `path` has an empty segment (see `FieldRef`'s `FromStr`).
+pub fn field(path: &str) -> Expr {
+    Expr::Field(path.parse().unwrap_or_else(|e| panic!("{}", e)))
+}
+
+/// A literal value: `lit(true)`, `lit(2.0)`, `lit("web")`, `lit(Literal::Null)`.
+pub fn lit(value: impl Into<Literal>) -> Expr {
+    Expr::Literal(value.into())
+}
+
+/// A regex literal, for use with `Expr::matches`.
+pub fn regex(pattern: &str) -> Expr {
+    Expr::Literal(Literal::Regex(pattern.to_string()))
+}
+
+/// A list literal of literals and/or fields, for use with `Expr::is_in`.
+///
+/// # Panics
+/// If an item is neither a scalar literal nor a field.
+pub fn list(items: impl IntoIterator<Item = Expr>) -> Expr {
+    let items = items.into_iter()
+        .map(|e| to_value(e).unwrap_or_else(|e| panic!("list items must be literals or fields, got {:?}", e)))
+        .collect();
+    Expr::Literal(Literal::List(items))
+}
+
+/// A function call: `call("len", [field("customer.tags")])`.
+pub fn call(name: &str, args: impl IntoIterator<Item = Expr>) -> Expr {
+    Expr::Call { name: name.to_string(), args: args.into_iter().collect() }
+}
+
+/// Logical negation: `!expr`.
+pub fn not(expr: Expr) -> Expr {
+    Expr::Not(Box::new(expr))
+}
+
+impl From<bool> for Literal {
+    fn from(b: bool) -> Self { Literal::Bool(b) }
+}
+
+impl From<f64> for Literal {
+    fn from(n: f64) -> Self { Literal::Number(n) }
+}
+
+impl From<&str> for Literal {
+    fn from(s: &str) -> Self { Literal::String(s.to_string()) }
+}
+
+impl From<String> for Literal {
+    fn from(s: String) -> Self { Literal::String(s) }
+}
+
+/// Comparison and logical combinators.
+///
+/// Like the parser, a comparison whose left side is a field and right side a scalar literal or
+/// field becomes `Expr::Compare`; anything else becomes `Expr::CompareExpr`.
+impl Expr {
+    /// `self == rhs`. Not named `eq` so that `PartialEq::eq` stays reachable by method syntax.
+    pub fn equals(self, rhs: Expr) -> Expr { self.compare(CompareOp::Eq, rhs) }
+    /// `self != rhs`; see `equals`.
+    pub fn not_equals(self, rhs: Expr) -> Expr { self.compare(CompareOp::Ne, rhs) }
+    pub fn lt(self, rhs: Expr) -> Expr { self.compare(CompareOp::Lt, rhs) }
+    pub fn le(self, rhs: Expr) -> Expr { self.compare(CompareOp::Le, rhs) }
+    pub fn gt(self, rhs: Expr) -> Expr { self.compare(CompareOp::Gt, rhs) }
+    pub fn ge(self, rhs: Expr) -> Expr { self.compare(CompareOp::Ge, rhs) }
+
+    pub fn and(self, rhs: Expr) -> Expr {
+        Expr::Logical { op: LogicalOp::And, lhs: Box::new(self), rhs: Box::new(rhs) }
+    }
+
+    pub fn or(self, rhs: Expr) -> Expr {
+        Expr::Logical { op: LogicalOp::Or, lhs: Box::new(self), rhs: Box::new(rhs) }
+    }
+
+    pub fn xor(self, rhs: Expr) -> Expr {
+        Expr::Logical { op: LogicalOp::Xor, lhs: Box::new(self), rhs: Box::new(rhs) }
+    }
+
+    /// `field in target`, where `target` is a `list(..)` or a set-valued field.
+    ///
+    /// # Panics
+    /// If `self` is not a field or `target` is not a literal or field.
+    pub fn is_in(self, target: Expr) -> Expr { self.membership(MembershipOp::In, target) }
+
+    /// `field not in target`; see `is_in`.
+    pub fn not_in(self, target: Expr) -> Expr { self.membership(MembershipOp::NotIn, target) }
+
+    /// `field contains value`.
+    ///
+    /// # Panics
+    /// If `self` is not a field or `value` is not a scalar literal or field.
+    pub fn contains(self, value: Expr) -> Expr {
+        let field = self.expect_field("contains");
+        let value = to_value(value).unwrap_or_else(|v| panic!("contains expects a literal or field, got {:?}", v));
+        Expr::Contains { field, op: ContainsOp::Contains, value }
+    }
+
+    /// `field not_contains value`; see `contains`.
+    pub fn not_contains(self, value: Expr) -> Expr {
+        let field = self.expect_field("not_contains");
+        let value = to_value(value).unwrap_or_else(|v| panic!("not_contains expects a literal or field, got {:?}", v));
+        Expr::Contains { field, op: ContainsOp::NotContains, value }
+    }
+
+    /// `field ~ pattern`, where `pattern` is a `regex(..)` or string literal.
+    ///
+    /// # Panics
+    /// If `self` is not a field or `pattern` is neither a regex nor a string literal.
+    pub fn matches(self, pattern: Expr) -> Expr {
+        let field = self.expect_field("matches");
+        match pattern {
+            Expr::Literal(Literal::Regex(pattern) | Literal::String(pattern)) => Expr::RegexMatch { field, pattern, anchored: false },
+            other => panic!("matches expects a regex or string literal, got {:?}", other),
+        }
+    }
+
+    fn compare(self, op: CompareOp, rhs: Expr) -> Expr {
+        match self {
+            Expr::Field(field) => match to_value(rhs) {
+                Ok(value) => Expr::Compare { field, op, value },
+                Err(rhs) => Expr::CompareExpr { op, lhs: Box::new(Expr::Field(field)), rhs },
+            },
+            lhs => Expr::CompareExpr { op, lhs: Box::new(lhs), rhs: Box::new(rhs) },
+        }
+    }
+
+    fn membership(self, op: MembershipOp, target: Expr) -> Expr {
+        let field = self.expect_field("membership");
+        let list = match target {
+            Expr::Literal(l) => LiteralOrField::Lit(l),
+            Expr::Field(fr) => LiteralOrField::Field(fr),
+            other => panic!("membership expects a list or field, got {:?}", other),
+        };
+        Expr::Membership { field, op, list }
+    }
+
+    fn expect_field(self, what: &str) -> FieldRef {
+        match self {
+            Expr::Field(fr) => fr,
+            other => panic!("{} requires a field on the left, got {:?}", what, other),
+        }
+    }
+}
+
+/// Scalar literals and fields become predicate values; anything else is handed back.
+fn to_value(expr: Expr) -> Result<LiteralOrField, Box<Expr>> {
+    match expr {
+        Expr::Field(fr) => Ok(LiteralOrField::Field(fr)),
+        Expr::Literal(Literal::List(_)) => Err(Box::new(expr)),
+        Expr::Literal(l) => Ok(LiteralOrField::Lit(l)),
+        other => Err(Box::new(other)),
+    }
+}
@@ -8,26 +8,160 @@
 //! - and storage/indexing tooling (dependency extraction, hashing).
 //!
 //! Key features:
-//! - `Expr`: the expression AST used across the system.
+//! - `Expr`: the expression AST used across the system (`Eq + Hash`, so usable as a map key).
+//! - `Expr::from_json` / `Expr::validate`: checked loading of stored ASTs (`AstError`).
+//! - `canonical_json_string`: byte-stable JSON text with sorted object keys.
 //! - `ast_hash`: stable hash of the canonical JSON representation (dedupe / caching).
-//! - `extract_dependencies`: walks the AST and returns referenced fields and functions.
+//! - `to_bytes` / `from_bytes` (feature `binary`): compact bincode encoding for storage.
+//! - `ast_hash_canonical`: like `ast_hash`, but ignores `&&` / `||` operand order.
+//! - `logically_equal`: structural equality modulo `&&` / `||` order and grouping.
+//! - `extract_dependencies`: walks the AST and returns referenced fields and functions (sorted via
+//!   `Dependencies::sorted_fields` / `sorted_functions`, and when serialized); `merge`, `|` and
+//!   `collect()` union them across expressions.
+//! - `extract_literals`: every embedded constant, for auditing stored rules.
+//! - `extract_dependencies_detailed`: like `extract_dependencies`, plus each field's `FieldUsage`s.
+//! - `depends_on_field` / `depends_on_any`: short-circuiting impact analysis.
+//! - `field_refs_with_paths`: every field reference with a stable structural path (UI mapping).
+//! - `rename_field`: migrates stored expressions when a dictionary field is renamed.
+//! - `dedupe_lists`: drops repeated literal entries from list literals.
+//! - `Visitor` / `walk_expr`: reusable read-only traversal for custom analyses.
+//! - `simplify`: conservative constant folding of literal-only subtrees.
+//! - `to_nnf`: negation-normal form (De Morgan) for caching and equivalence checks.
+//! - `ast_depth` / `ast_node_count` / `validate_complexity`: size limits for untrusted input.
+//! - `schema` (feature `schemars`): JSON Schema of the serialized `Expr`, for frontends.
+//! - `builder`: fluent constructors (`field("x").gt(lit(2.0))`) for codegen.
 
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
 use sha2::{Digest, Sha256};
+use thiserror::Error;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub mod builder;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 /// A dotted path reference like `customer.is_known`.
 ///
 /// Stored as a vector of identifiers to avoid repeated splitting.
+/// Trailing all-digit segments are positional indices into a set value, so
+/// `signal.tags[0]` (or `signal.tags.0`) is `["signal", "tags", "0"]`.
 pub struct FieldRef {
     pub path: Vec<String>,
+    /// `path` joined with dots, computed on first use by `dotted` and shared by clones.
+    #[serde(skip)]
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    dotted: OnceLock<Arc<str>>,
 }
 impl FieldRef {
-    pub fn new(path: Vec<String>) -> Self { Self { path } }
+    pub fn new(path: Vec<String>) -> Self { Self { path, dotted: OnceLock::new() } }
     pub fn as_dotted(&self) -> String { self.path.join(".") }
+
+    /// Dotted form without allocating after the first call, for hot lookups such as
+    /// `EvalContext::get`. If `path` was mutated since, the fresh join is returned instead.
+    pub fn dotted(&self) -> Cow<'_, str> {
+        let cached = self.dotted.get_or_init(|| Arc::from(self.path.join(".")));
+        if dotted_matches(cached, &self.path) { Cow::Borrowed(cached) } else { Cow::Owned(self.as_dotted()) }
+    }
+
+    /// Number of leading named segments, i.e. where the index segments start.
+    fn base_len(&self) -> usize {
+        self.path.iter().position(|seg| is_index_segment(seg)).unwrap_or(self.path.len())
+    }
+
+    /// The field without index segments (`signal.tags` for `signal.tags[0]`).
+    pub fn base(&self) -> FieldRef { FieldRef::new(self.path[..self.base_len()].to_vec()) }
+
+    /// Dotted form of `base()`.
+    pub fn base_dotted(&self) -> String { self.path[..self.base_len()].join(".") }
+
+    /// Checks the path is well formed: a non-empty run of identifiers (`[A-Za-z_][A-Za-z0-9_]*`)
+    /// optionally followed by index segments (all digits). Rejects empty segments (`a..b`,
+    /// trailing dots) and stray whitespace (`a. b`), which would never match a context key.
+    pub fn validate(&self) -> Result<(), ParseFieldRefError> {
+        let err = |reason: &str| ParseFieldRefError { path: self.as_dotted(), reason: reason.to_string() };
+        let base = self.base_len();
+        if base == 0 { return Err(err("must start with an identifier")); }
+        for (i, seg) in self.path.iter().enumerate() {
+            if seg.is_empty() { return Err(err("empty segment")); }
+            if i >= base {
+                if !is_index_segment(seg) { return Err(err("identifier after index segment")); }
+                continue;
+            }
+            let mut chars = seg.chars();
+            let head_ok = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+            if !head_ok || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(err(&format!("invalid segment {:?}", seg)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Positional indices applied to the base field's value, outermost first.
+    pub fn indices(&self) -> Vec<usize> {
+        self.path[self.base_len()..].iter().filter_map(|seg| seg.parse().ok()).collect()
+    }
+}
+
+fn dotted_matches(dotted: &str, path: &[String]) -> bool {
+    let mut rest = dotted;
+    for (i, seg) in path.iter().enumerate() {
+        if i > 0 {
+            match rest.strip_prefix('.') { Some(r) => rest = r, None => return false }
+        }
+        match rest.strip_prefix(seg.as_str()) { Some(r) => rest = r, None => return false }
+    }
+    rest.is_empty()
+}
+
+// Equality, hashing and `Debug` depend on `path` alone, never on the dotted cache.
+impl PartialEq for FieldRef {
+    fn eq(&self, other: &Self) -> bool { self.path == other.path }
+}
+
+impl Eq for FieldRef {}
+
+impl std::hash::Hash for FieldRef {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) { self.path.hash(state) }
+}
+
+impl std::fmt::Debug for FieldRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldRef").field("path", &self.path).finish()
+    }
+}
+
+/// Dotted form, same as `as_dotted`.
+impl std::fmt::Display for FieldRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.path.join("."))
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid field reference {path:?}: {reason}")]
+pub struct ParseFieldRefError {
+    pub path: String,
+    pub reason: String,
+}
+
+/// Parses a dotted path (`customer.tags.0`), checked with `FieldRef::validate`.
+impl std::str::FromStr for FieldRef {
+    type Err = ParseFieldRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fr = FieldRef::new(s.split('.').map(str::to_string).collect());
+        fr.validate()?;
+        Ok(fr)
+    }
+}
+
+fn is_index_segment(seg: &str) -> bool {
+    !seg.is_empty() && seg.bytes().all(|b| b.is_ascii_digit())
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum Literal {
     Bool(bool),
     Number(f64),
@@ -35,107 +169,987 @@ pub enum Literal {
     Null,
     Regex(String),
     List(Vec<LiteralOrField>),
+    /// Integer range `start..end`, or `start..=end` when `inclusive`; a membership target
+    /// checked arithmetically rather than expanded into a list.
+    Range { start: i64, end: i64, inclusive: bool },
+}
+
+// Structural equality, so that `Expr` can be `Eq` and `Hash` (e.g. as a cache key): numbers
+// compare as `f64` except that NaN equals NaN. Hashing normalizes NaN payloads and `-0.0`
+// accordingly.
+impl PartialEq for Literal {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Literal::Bool(a), Literal::Bool(b)) => a == b,
+            (Literal::Number(a), Literal::Number(b)) => a == b || (a.is_nan() && b.is_nan()),
+            (Literal::String(a), Literal::String(b)) | (Literal::Regex(a), Literal::Regex(b)) => a == b,
+            (Literal::Null, Literal::Null) => true,
+            (Literal::List(a), Literal::List(b)) => a == b,
+            (
+                Literal::Range { start: s1, end: e1, inclusive: i1 },
+                Literal::Range { start: s2, end: e2, inclusive: i2 },
+            ) => s1 == s2 && e1 == e2 && i1 == i2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Literal {}
+
+impl std::hash::Hash for Literal {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Literal::Bool(b) => b.hash(state),
+            Literal::Number(n) => {
+                let bits = if n.is_nan() { f64::NAN.to_bits() } else if *n == 0.0 { 0 } else { n.to_bits() };
+                bits.hash(state)
+            }
+            Literal::String(s) | Literal::Regex(s) => s.hash(state),
+            Literal::Null => {}
+            Literal::List(items) => items.hash(state),
+            Literal::Range { start, end, inclusive } => (start, end, inclusive).hash(state),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum LiteralOrField {
     Lit(Literal),
     Field(FieldRef),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum CompareOp { Eq, Ne, Lt, Le, Gt, Ge }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum MembershipOp { In, NotIn }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum ContainsOp { Contains }
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+/// Substring/element operators sharing the `Contains` node.
+///
+/// `StartsWith`/`EndsWith` apply to strings only; `Contains` also tests set membership and
+/// `NotContains` is its negation.
+pub enum ContainsOp { Contains, NotContains, StartsWith, EndsWith }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum LogicalOp { And, Or }
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+/// `Xor` is true when exactly one side is; unlike `And`/`Or` it always evaluates both.
+pub enum LogicalOp { And, Or, Xor }
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum ArithOp { Add, Sub, Mul, Div, Mod }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 /// Expression AST.
 ///
 /// Notes:
 /// - Logical ops use short-circuit at runtime.
 /// - `Call` is for extensible functions resolved via a registry.
+/// - `Compare` is the field-vs-value predicate; `CompareExpr` compares two
+///   arbitrary operands (e.g. arithmetic or call results).
 pub enum Expr {
     Not(Box<Expr>),
     Logical { op: LogicalOp, lhs: Box<Expr>, rhs: Box<Expr> },
+    Arithmetic { op: ArithOp, lhs: Box<Expr>, rhs: Box<Expr> },
     Compare { field: FieldRef, op: CompareOp, value: LiteralOrField },
+    CompareExpr { op: CompareOp, lhs: Box<Expr>, rhs: Box<Expr> },
     Membership { field: FieldRef, op: MembershipOp, list: LiteralOrField },
+    /// `field between low and high`; bounds are included unless `inclusive` is false.
+    Between { field: FieldRef, low: LiteralOrField, high: LiteralOrField, inclusive: bool },
     Contains { field: FieldRef, op: ContainsOp, value: LiteralOrField },
-    RegexMatch { field: FieldRef, pattern: String },
+    /// `field ~ pattern` finds the pattern anywhere in the string; with `anchored` (`~=`)
+    /// it must match the whole string.
+    RegexMatch {
+        field: FieldRef,
+        pattern: String,
+        #[serde(default)]
+        anchored: bool,
+    },
     Call { name: String, args: Vec<Expr> },
+    /// `cond ? then : els`; only the taken branch is evaluated.
+    If { cond: Box<Expr>, then: Box<Expr>, els: Box<Expr> },
     Literal(Literal),
     Field(FieldRef),
 }
 
+/// JSON Schema (draft 7) describing `canonical_json` output: `Expr` at the root, with
+/// `Literal` and the other AST types under `definitions`. Derived from the same types as the
+/// serde representation, so the two cannot drift.
+#[cfg(feature = "schemars")]
+pub fn schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(Expr)).expect("schema serializable")
+}
+
+/// Why a stored AST was rejected by `Expr::from_json`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AstError {
+    #[error("malformed AST JSON: {0}")]
+    Json(String),
+    #[error(transparent)]
+    InvalidField(#[from] ParseFieldRefError),
+    #[error("invalid function name {0:?}")]
+    InvalidCallName(String),
+    #[error("invalid regex {pattern:?}: {reason}")]
+    InvalidRegex { pattern: String, reason: String },
+}
+
+impl Expr {
+    /// Deserializes a stored AST (the `canonical_json` shape) and checks it with `validate`.
+    pub fn from_json(v: serde_json::Value) -> Result<Expr, AstError> {
+        let expr: Expr = serde_json::from_value(v).map_err(|e| AstError::Json(e.to_string()))?;
+        expr.validate()?;
+        Ok(expr)
+    }
+
+    /// Checks invariants the parser guarantees but deserialization does not: every field path
+    /// passes `FieldRef::validate`, call names are identifiers, and regex patterns (of `~`
+    /// matches and `Literal::Regex` values) compile. Reports the first violation found.
+    pub fn validate(&self) -> Result<(), AstError> {
+        struct Check(Option<AstError>);
+        impl Check {
+            fn fail(&mut self, e: AstError) { self.0.get_or_insert(e); }
+            fn regex(&mut self, pattern: &str) {
+                if let Err(e) = regex::Regex::new(pattern) {
+                    self.fail(AstError::InvalidRegex { pattern: pattern.to_string(), reason: e.to_string() });
+                }
+            }
+        }
+        impl Visitor for Check {
+            fn visit_field(&mut self, field: &FieldRef) {
+                if let Err(e) = field.validate() { self.fail(e.into()); }
+            }
+            fn visit_call(&mut self, name: &str, _args: &[Expr]) {
+                let mut chars = name.chars();
+                let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid { self.fail(AstError::InvalidCallName(name.to_string())); }
+            }
+            fn visit_literal(&mut self, literal: &Literal) {
+                if let Literal::Regex(pattern) = literal { self.regex(pattern); }
+            }
+            fn visit_regex(&mut self, pattern: &str) { self.regex(pattern); }
+        }
+        let mut check = Check(None);
+        walk_expr(self, &mut check);
+        check.0.map_or(Ok(()), Err)
+    }
+}
+
 pub fn canonical_json(expr: &Expr) -> serde_json::Value {
     serde_json::to_value(expr).expect("Expr serializable")
 }
 
+/// Compact JSON text of `expr` with every object's keys sorted, so the bytes do not depend on
+/// serde_json's map implementation (e.g. its `preserve_order` feature being enabled elsewhere).
+pub fn canonical_json_string(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_sorted_json(&canonical_json(expr), &mut out);
+    out
+}
+
+fn write_sorted_json(v: &serde_json::Value, out: &mut String) {
+    match v {
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 { out.push(','); }
+                write_sorted_json(item, out);
+            }
+            out.push(']');
+        }
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (k, item)) in entries.into_iter().enumerate() {
+                if i > 0 { out.push(','); }
+                out.push_str(&serde_json::to_string(k).expect("json"));
+                out.push(':');
+                write_sorted_json(item, out);
+            }
+            out.push('}');
+        }
+        scalar => out.push_str(&serde_json::to_string(scalar).expect("json")),
+    }
+}
+
+/// SHA-256 (hex) of `canonical_json_string`.
 pub fn ast_hash(expr: &Expr) -> String {
-    let v = canonical_json(expr);
-    let bytes = serde_json::to_vec(&v).expect("json");
     let mut hasher = Sha256::new();
-    hasher.update(bytes);
+    hasher.update(canonical_json_string(expr).as_bytes());
     hex::encode(hasher.finalize())
 }
 
+/// Compact binary encoding of `expr` (bincode), for storage where JSON is too verbose.
+///
+/// Hashing is unaffected: `ast_hash` always works over canonical JSON.
+#[cfg(feature = "binary")]
+pub fn to_bytes(expr: &Expr) -> Vec<u8> {
+    bincode::serialize(expr).expect("Expr serializable")
+}
+
+/// Decodes an `Expr` produced by `to_bytes`.
+#[cfg(feature = "binary")]
+pub fn from_bytes(bytes: &[u8]) -> Result<Expr, bincode::Error> {
+    bincode::deserialize(bytes)
+}
+
+/// Like `ast_hash`, but insensitive to the order of `&&` / `||` operands.
+///
+/// Chains of the same logical operator are flattened and their operands sorted by hash before
+/// hashing, so `a && b`, `b && a` and `(a && b) && c` vs `a && (b && c)` collide. Meant for
+/// deduping logically identical predicates; use `ast_hash` when the stored shape matters.
+pub fn ast_hash_canonical(expr: &Expr) -> String {
+    ast_hash(&sort_logical(expr))
+}
+
+/// True if `a` and `b` are the same expression up to the order and grouping of `&&` / `||`
+/// operands (`a && (b && c)` equals `(c && a) && b`).
+///
+/// Purely structural: no SAT reasoning, so `a || !a` is not equal to `true`.
+pub fn logically_equal(a: &Expr, b: &Expr) -> bool {
+    sort_logical(a) == sort_logical(b)
+}
+
+/// Rebuilds `expr` with every `&&` / `||` chain flattened, sorted by `ast_hash` and left-folded.
+fn sort_logical(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Not(e) => Expr::Not(Box::new(sort_logical(e))),
+        Expr::Logical { op, .. } => {
+            let mut operands = Vec::new();
+            flatten_logical(expr, op, &mut operands);
+            let mut keyed: Vec<(String, Expr)> = operands.into_iter()
+                .map(|e| { let e = sort_logical(e); (ast_hash(&e), e) })
+                .collect();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut it = keyed.into_iter().map(|(_, e)| e);
+            let first = it.next().expect("logical chain has operands");
+            it.fold(first, |lhs, rhs| Expr::Logical { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) })
+        }
+        Expr::Arithmetic { op, lhs, rhs } => Expr::Arithmetic { op: op.clone(), lhs: Box::new(sort_logical(lhs)), rhs: Box::new(sort_logical(rhs)) },
+        Expr::CompareExpr { op, lhs, rhs } => Expr::CompareExpr { op: op.clone(), lhs: Box::new(sort_logical(lhs)), rhs: Box::new(sort_logical(rhs)) },
+        Expr::Call { name, args } => Expr::Call { name: name.clone(), args: args.iter().map(sort_logical).collect() },
+        Expr::If { cond, then, els } => Expr::If { cond: Box::new(sort_logical(cond)), then: Box::new(sort_logical(then)), els: Box::new(sort_logical(els)) },
+        _ => expr.clone(),
+    }
+}
+
+fn flatten_logical<'e>(expr: &'e Expr, op: &LogicalOp, out: &mut Vec<&'e Expr>) {
+    match expr {
+        Expr::Logical { op: o, lhs, rhs } if o == op => {
+            flatten_logical(lhs, op, out);
+            flatten_logical(rhs, op, out);
+        }
+        _ => out.push(expr),
+    }
+}
+
+/// Conservative constant folding.
+///
+/// Folds only literal-only subtrees and the literal side of logical ops:
+/// - `true && X => X`, `false && X => false`, `true || X => true`, `false || X => X`
+///   (and `X && true => X`, `X || false => X`; `X && false` is kept so `X` can still error),
+/// - `!true => false`, `!!X => X`,
+/// - comparisons and arithmetic between two literals (division by zero is left as-is), and
+///   concatenation of two string literals,
+/// - `true ? a : b => a`, `false ? a : b => b`.
+///
+/// Subtrees touching a field or call are otherwise returned unchanged. The result is a fixpoint:
+/// `simplify(&simplify(e)) == simplify(e)`.
+pub fn simplify(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Not(e) => match simplify(e) {
+            Expr::Literal(Literal::Bool(b)) => Expr::Literal(Literal::Bool(!b)),
+            Expr::Not(inner) => *inner,
+            e => Expr::Not(Box::new(e)),
+        },
+        Expr::Logical { op, lhs, rhs } => {
+            let (lhs, rhs) = (simplify(lhs), simplify(rhs));
+            match (op, &lhs, &rhs) {
+                (LogicalOp::And, Expr::Literal(Literal::Bool(true)), _) => rhs,
+                (LogicalOp::And, Expr::Literal(Literal::Bool(false)), _) => lhs,
+                (LogicalOp::Or, Expr::Literal(Literal::Bool(true)), _) => lhs,
+                (LogicalOp::Or, Expr::Literal(Literal::Bool(false)), _) => rhs,
+                (LogicalOp::And, _, Expr::Literal(Literal::Bool(true))) => lhs,
+                (LogicalOp::Or, _, Expr::Literal(Literal::Bool(false))) => lhs,
+                (LogicalOp::Xor, Expr::Literal(Literal::Bool(a)), Expr::Literal(Literal::Bool(b))) => Expr::Literal(Literal::Bool(a != b)),
+                (LogicalOp::Xor, Expr::Literal(Literal::Bool(false)), _) => rhs,
+                (LogicalOp::Xor, _, Expr::Literal(Literal::Bool(false))) => lhs,
+                _ => Expr::Logical { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) },
+            }
+        }
+        Expr::Arithmetic { op, lhs, rhs } => {
+            let (lhs, rhs) = (simplify(lhs), simplify(rhs));
+            match (&lhs, &rhs) {
+                (Expr::Literal(Literal::Number(x)), Expr::Literal(Literal::Number(y))) => match fold_arith(op, *x, *y) {
+                    Some(n) => Expr::Literal(Literal::Number(n)),
+                    None => Expr::Arithmetic { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) },
+                },
+                (Expr::Literal(Literal::String(x)), Expr::Literal(Literal::String(y))) if *op == ArithOp::Add => {
+                    Expr::Literal(Literal::String(format!("{}{}", x, y)))
+                }
+                _ => Expr::Arithmetic { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) },
+            }
+        }
+        Expr::CompareExpr { op, lhs, rhs } => {
+            let (lhs, rhs) = (simplify(lhs), simplify(rhs));
+            match (&lhs, &rhs) {
+                (Expr::Literal(a), Expr::Literal(b)) => match fold_compare(op, a, b) {
+                    Some(r) => Expr::Literal(Literal::Bool(r)),
+                    None => Expr::CompareExpr { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) },
+                },
+                _ => Expr::CompareExpr { op: op.clone(), lhs: Box::new(lhs), rhs: Box::new(rhs) },
+            }
+        }
+        Expr::If { cond, then, els } => match simplify(cond) {
+            Expr::Literal(Literal::Bool(true)) => simplify(then),
+            Expr::Literal(Literal::Bool(false)) => simplify(els),
+            cond => Expr::If { cond: Box::new(cond), then: Box::new(simplify(then)), els: Box::new(simplify(els)) },
+        },
+        _ => expr.clone(),
+    }
+}
+
+/// Rewrites `expr` into negation-normal form: `Not` is pushed down to the leaves.
+///
+/// - De Morgan: `!(a && b) => !a || !b`, `!(a || b) => !a && !b`,
+/// - comparisons flip under negation (`!(x > y) => x <= y`), as do `in` / `not in`,
+/// - `!!x => x` and `!true => false`,
+/// - `!(c ? a : b) => c ? !a : !b`,
+/// - `!(a ^^ b) => a ^^ !b`.
+///
+/// `contains` / `not_contains` flip into each other; negations of other leaves (`starts_with`,
+/// regex, calls, fields, `between`) stay as `Not`.
+/// The comparison flip assumes a total order; the evaluator rejects NaN operands, so it holds.
+/// `to_nnf` is idempotent.
+pub fn to_nnf(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Not(e) => negate_nnf(e),
+        Expr::Logical { op, lhs, rhs } => Expr::Logical { op: op.clone(), lhs: Box::new(to_nnf(lhs)), rhs: Box::new(to_nnf(rhs)) },
+        Expr::Call { name, args } => Expr::Call { name: name.clone(), args: args.iter().map(to_nnf).collect() },
+        Expr::If { cond, then, els } => Expr::If { cond: Box::new(to_nnf(cond)), then: Box::new(to_nnf(then)), els: Box::new(to_nnf(els)) },
+        _ => expr.clone(),
+    }
+}
+
+/// NNF of `!expr`.
+fn negate_nnf(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Not(e) => to_nnf(e),
+        Expr::Logical { op: LogicalOp::Xor, lhs, rhs } => {
+            Expr::Logical { op: LogicalOp::Xor, lhs: Box::new(to_nnf(lhs)), rhs: Box::new(negate_nnf(rhs)) }
+        }
+        Expr::Logical { op, lhs, rhs } => {
+            let op = match op { LogicalOp::And => LogicalOp::Or, _ => LogicalOp::And };
+            Expr::Logical { op, lhs: Box::new(negate_nnf(lhs)), rhs: Box::new(negate_nnf(rhs)) }
+        }
+        Expr::Compare { field, op, value } => Expr::Compare { field: field.clone(), op: negate_compare(op), value: value.clone() },
+        Expr::CompareExpr { op, lhs, rhs } => Expr::CompareExpr { op: negate_compare(op), lhs: lhs.clone(), rhs: rhs.clone() },
+        Expr::Membership { field, op, list } => {
+            let op = match op { MembershipOp::In => MembershipOp::NotIn, MembershipOp::NotIn => MembershipOp::In };
+            Expr::Membership { field: field.clone(), op, list: list.clone() }
+        }
+        Expr::Contains { field, op: op @ (ContainsOp::Contains | ContainsOp::NotContains), value } => {
+            let op = match op { ContainsOp::Contains => ContainsOp::NotContains, _ => ContainsOp::Contains };
+            Expr::Contains { field: field.clone(), op, value: value.clone() }
+        }
+        Expr::If { cond, then, els } => Expr::If { cond: Box::new(to_nnf(cond)), then: Box::new(negate_nnf(then)), els: Box::new(negate_nnf(els)) },
+        Expr::Literal(Literal::Bool(b)) => Expr::Literal(Literal::Bool(!b)),
+        _ => Expr::Not(Box::new(to_nnf(expr))),
+    }
+}
+
+fn negate_compare(op: &CompareOp) -> CompareOp {
+    match op {
+        CompareOp::Eq => CompareOp::Ne, CompareOp::Ne => CompareOp::Eq,
+        CompareOp::Lt => CompareOp::Ge, CompareOp::Ge => CompareOp::Lt,
+        CompareOp::Le => CompareOp::Gt, CompareOp::Gt => CompareOp::Le,
+    }
+}
+
+fn fold_arith(op: &ArithOp, x: f64, y: f64) -> Option<f64> {
+    match op {
+        ArithOp::Add => Some(x + y),
+        ArithOp::Sub => Some(x - y),
+        ArithOp::Mul => Some(x * y),
+        ArithOp::Div | ArithOp::Mod if y == 0.0 => None,
+        ArithOp::Div => Some(x / y),
+        ArithOp::Mod => Some(x % y),
+    }
+}
+
+/// Mirrors the evaluator's comparison rules; `None` for pairs it would reject at runtime.
+fn fold_compare(op: &CompareOp, a: &Literal, b: &Literal) -> Option<bool> {
+    fn ordered<T: PartialOrd>(op: &CompareOp, x: T, y: T) -> bool {
+        match op {
+            CompareOp::Eq => x == y, CompareOp::Ne => x != y,
+            CompareOp::Lt => x < y, CompareOp::Le => x <= y,
+            CompareOp::Gt => x > y, CompareOp::Ge => x >= y,
+        }
+    }
+    match (a, b, op) {
+        (Literal::Number(x), Literal::Number(y), _) => Some(ordered(op, x, y)),
+        (Literal::String(x), Literal::String(y), _) => Some(ordered(op, x, y)),
+        (Literal::Bool(x), Literal::Bool(y), CompareOp::Eq | CompareOp::Ne) => Some(ordered(op, x, y)),
+        (Literal::Null, Literal::Null, CompareOp::Eq) => Some(true),
+        (Literal::Null, Literal::Null, CompareOp::Ne) => Some(false),
+        (Literal::Null, _, CompareOp::Eq) | (_, Literal::Null, CompareOp::Eq) => Some(false),
+        (Literal::Null, _, CompareOp::Ne) | (_, Literal::Null, CompareOp::Ne) => Some(true),
+        _ => None,
+    }
+}
+
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-/// Dependencies extracted from an expression: field references and function calls.
+/// Dependencies extracted from an expression: field references, function calls and regex patterns.
 ///
 /// This powers:
 /// - deploy-time validation (detect unknown fields/functions),
 /// - storage indexing (`expression_dependencies`),
-/// - impact analysis (“what breaks if field X changes?”).
+/// - impact analysis (“what breaks if field X changes?”),
+/// - auditing of embedded regex patterns.
+///
+/// Serializes as `{"fields": [...], "functions": [...], "regexes": [...]}` with each array
+/// sorted, so stored output and snapshots are deterministic.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Dependencies {
     pub fields: HashSet<String>,
     pub functions: HashSet<String>,
+    /// Patterns from `~` matches and `Literal::Regex` values (including inside lists).
+    pub regexes: HashSet<String>,
+}
+
+impl Dependencies {
+    /// `fields` in lexicographic order.
+    pub fn sorted_fields(&self) -> Vec<String> { sorted(&self.fields) }
+
+    /// `functions` in lexicographic order.
+    pub fn sorted_functions(&self) -> Vec<String> { sorted(&self.functions) }
+
+    /// `regexes` in lexicographic order.
+    pub fn sorted_regexes(&self) -> Vec<String> { sorted(&self.regexes) }
+
+    /// Adds everything `other` depends on.
+    pub fn merge(&mut self, other: &Dependencies) {
+        self.fields.extend(other.fields.iter().cloned());
+        self.functions.extend(other.functions.iter().cloned());
+        self.regexes.extend(other.regexes.iter().cloned());
+    }
+}
+
+/// Union of two dependency sets.
+impl std::ops::BitOr for Dependencies {
+    type Output = Dependencies;
+
+    fn bitor(mut self, rhs: Dependencies) -> Dependencies {
+        self.merge(&rhs);
+        self
+    }
+}
+
+/// Union over many expressions: `exprs.iter().map(extract_dependencies).collect()`.
+impl FromIterator<Dependencies> for Dependencies {
+    fn from_iter<I: IntoIterator<Item = Dependencies>>(iter: I) -> Self {
+        iter.into_iter().fold(Dependencies::default(), |acc, d| acc | d)
+    }
 }
 
-/// Walks the AST and returns the set of dotted field references and function names.
+fn sorted(set: &HashSet<String>) -> Vec<String> {
+    let mut v: Vec<String> = set.iter().cloned().collect();
+    v.sort();
+    v
+}
+
+impl Serialize for Dependencies {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut st = serializer.serialize_struct("Dependencies", 3)?;
+        st.serialize_field("fields", &self.sorted_fields())?;
+        st.serialize_field("functions", &self.sorted_functions())?;
+        st.serialize_field("regexes", &self.sorted_regexes())?;
+        st.end()
+    }
+}
+
+/// Walks the AST and returns the set of dotted field references, function names and regex patterns.
 pub fn extract_dependencies(expr: &Expr) -> Dependencies {
     let mut d = Dependencies::default();
-    walk(expr, &mut d);
+    walk_expr(expr, &mut d);
     d
 }
 
-fn add_field(d: &mut Dependencies, fr: &FieldRef) {
-    d.fields.insert(fr.as_dotted());
+impl Visitor for Dependencies {
+    fn visit_field(&mut self, field: &FieldRef) {
+        self.fields.insert(field.base_dotted());
+    }
+
+    fn visit_call(&mut self, name: &str, _args: &[Expr]) {
+        self.functions.insert(name.to_string());
+    }
+
+    fn visit_literal(&mut self, literal: &Literal) {
+        if let Literal::Regex(pattern) = literal {
+            self.regexes.insert(pattern.clone());
+        }
+    }
+
+    fn visit_regex(&mut self, pattern: &str) {
+        self.regexes.insert(pattern.to_string());
+    }
+}
+
+/// Every constant in `expr`, in `walk_expr` order with repeats kept: comparison values, bounds,
+/// bare literals and the elements of lists (not the lists themselves). Regex patterns of `~`
+/// matches are included as `Literal::Regex`.
+pub fn extract_literals(expr: &Expr) -> Vec<Literal> {
+    struct Literals(Vec<Literal>);
+    impl Visitor for Literals {
+        fn visit_literal(&mut self, literal: &Literal) {
+            if !matches!(literal, Literal::List(_)) { self.0.push(literal.clone()); }
+        }
+        fn visit_regex(&mut self, pattern: &str) { self.0.push(Literal::Regex(pattern.to_string())); }
+    }
+    let mut v = Literals(Vec::new());
+    walk_expr(expr, &mut v);
+    v.0
+}
+
+/// How a field is used within an expression; see `extract_dependencies_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FieldUsage {
+    /// Subject of a predicate (`x` in `x > 1`), or an operand of arithmetic, a call or `?:`.
+    Tested,
+    /// Right-hand side of a comparison, a `between` bound or a `contains` value.
+    ComparedAgainst,
+    /// Element of a list literal, or the target of a membership test (`y` in `x in y`).
+    InList,
+    /// Subject of a regex match.
+    RegexTarget,
+}
+
+/// `Dependencies` plus the roles each field plays, for impact prioritization.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DetailedDependencies {
+    pub dependencies: Dependencies,
+    /// Keyed like `Dependencies::fields` (base dotted name); a field used several ways has
+    /// several usages.
+    pub usages: HashMap<String, HashSet<FieldUsage>>,
+}
+
+pub fn extract_dependencies_detailed(expr: &Expr) -> DetailedDependencies {
+    let mut usages = HashMap::new();
+    collect_usages(expr, &mut usages);
+    DetailedDependencies { dependencies: extract_dependencies(expr), usages }
+}
+
+fn collect_usages(expr: &Expr, out: &mut HashMap<String, HashSet<FieldUsage>>) {
+    match expr {
+        Expr::Not(e) => collect_usages(e, out),
+        Expr::Logical { lhs, rhs, .. } | Expr::Arithmetic { lhs, rhs, .. } => {
+            collect_usages(lhs, out);
+            collect_usages(rhs, out);
+        }
+        Expr::CompareExpr { lhs, rhs, .. } => {
+            collect_usages(lhs, out);
+            match &**rhs {
+                Expr::Field(fr) => add_usage(out, fr, FieldUsage::ComparedAgainst),
+                rhs => collect_usages(rhs, out),
+            }
+        }
+        Expr::Compare { field, value, .. } | Expr::Contains { field, value, .. } => {
+            add_usage(out, field, FieldUsage::Tested);
+            collect_value_usages(value, FieldUsage::ComparedAgainst, out);
+        }
+        Expr::Membership { field, list, .. } => {
+            add_usage(out, field, FieldUsage::Tested);
+            collect_value_usages(list, FieldUsage::InList, out);
+        }
+        Expr::Between { field, low, high, .. } => {
+            add_usage(out, field, FieldUsage::Tested);
+            collect_value_usages(low, FieldUsage::ComparedAgainst, out);
+            collect_value_usages(high, FieldUsage::ComparedAgainst, out);
+        }
+        Expr::RegexMatch { field, .. } => add_usage(out, field, FieldUsage::RegexTarget),
+        Expr::Call { args, .. } => for a in args { collect_usages(a, out); },
+        Expr::If { cond, then, els } => {
+            collect_usages(cond, out);
+            collect_usages(then, out);
+            collect_usages(els, out);
+        }
+        Expr::Literal(l) => collect_literal_usages(l, out),
+        Expr::Field(fr) => add_usage(out, fr, FieldUsage::Tested),
+    }
 }
 
-fn add_lit_or_field(d: &mut Dependencies, v: &LiteralOrField) {
+fn collect_value_usages(v: &LiteralOrField, usage: FieldUsage, out: &mut HashMap<String, HashSet<FieldUsage>>) {
     match v {
-        LiteralOrField::Field(fr) => add_field(d, fr),
-        LiteralOrField::Lit(Literal::List(items)) => {
-            for it in items {
-                add_lit_or_field(d, it);
+        LiteralOrField::Lit(l) => collect_literal_usages(l, out),
+        LiteralOrField::Field(fr) => add_usage(out, fr, usage),
+    }
+}
+
+fn add_usage(out: &mut HashMap<String, HashSet<FieldUsage>>, fr: &FieldRef, usage: FieldUsage) {
+    out.entry(fr.base_dotted()).or_default().insert(usage);
+}
+
+fn collect_literal_usages(l: &Literal, out: &mut HashMap<String, HashSet<FieldUsage>>) {
+    if let Literal::List(items) = l {
+        for item in items { collect_value_usages(item, FieldUsage::InList, out); }
+    }
+}
+
+/// True if `expr` references `field` (dotted, compared like `Dependencies::fields`, so
+/// `tags[0]` depends on `tags`). Stops at the first match instead of collecting every dependency.
+pub fn depends_on_field(expr: &Expr, field: &str) -> bool {
+    any_field(expr, &mut |fr| fr.base_dotted() == field)
+}
+
+/// True if `expr` references any of `fields`; stops at the first match.
+pub fn depends_on_any(expr: &Expr, fields: &HashSet<String>) -> bool {
+    any_field(expr, &mut |fr| fields.contains(&fr.base_dotted()))
+}
+
+/// Every field reference in `expr`, in `walk_expr` order, with the structural path to it.
+///
+/// A path names each node on the way down followed by the child slot taken, e.g. `a && b > 1`
+/// yields `b` at `["and", "rhs", "compare", "field"]`. Node names are `not`, `and`, `or`,
+/// `arithmetic`, `compare`, `compare_expr`, `membership`, `between`, `contains`, `regex_match`,
+/// `call`, `if`, `literal` and `field`; call arguments and list items are addressed by index
+/// (`["call", "args", "0", "field"]`, `["membership", "list", "1"]`). Paths depend only on the
+/// tree's shape, so equal trees give equal paths and repeated fields get distinct ones.
+pub fn field_refs_with_paths(expr: &Expr) -> Vec<(FieldRef, Vec<String>)> {
+    let mut out = Vec::new();
+    collect_field_paths(expr, &mut Vec::new(), &mut out);
+    out
+}
+
+fn collect_field_paths(expr: &Expr, path: &mut Vec<String>, out: &mut Vec<(FieldRef, Vec<String>)>) {
+    let kind = match expr {
+        Expr::Not(_) => "not",
+        Expr::Logical { op: LogicalOp::And, .. } => "and",
+        Expr::Logical { op: LogicalOp::Or, .. } => "or",
+        Expr::Logical { op: LogicalOp::Xor, .. } => "xor",
+        Expr::Arithmetic { .. } => "arithmetic",
+        Expr::Compare { .. } => "compare",
+        Expr::CompareExpr { .. } => "compare_expr",
+        Expr::Membership { .. } => "membership",
+        Expr::Between { .. } => "between",
+        Expr::Contains { .. } => "contains",
+        Expr::RegexMatch { .. } => "regex_match",
+        Expr::Call { .. } => "call",
+        Expr::If { .. } => "if",
+        Expr::Literal(_) => "literal",
+        Expr::Field(_) => "field",
+    };
+    path.push(kind.to_string());
+    match expr {
+        Expr::Not(e) => with_segment(path, "expr", |p| collect_field_paths(e, p, out)),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => {
+            with_segment(path, "lhs", |p| collect_field_paths(lhs, p, out));
+            with_segment(path, "rhs", |p| collect_field_paths(rhs, p, out));
+        }
+        Expr::Compare { field, value, .. } | Expr::Contains { field, value, .. } => {
+            with_segment(path, "field", |p| out.push((field.clone(), p.clone())));
+            with_segment(path, "value", |p| collect_value_paths(value, p, out));
+        }
+        Expr::Membership { field, list, .. } => {
+            with_segment(path, "field", |p| out.push((field.clone(), p.clone())));
+            with_segment(path, "list", |p| collect_value_paths(list, p, out));
+        }
+        Expr::Between { field, low, high, .. } => {
+            with_segment(path, "field", |p| out.push((field.clone(), p.clone())));
+            with_segment(path, "low", |p| collect_value_paths(low, p, out));
+            with_segment(path, "high", |p| collect_value_paths(high, p, out));
+        }
+        Expr::RegexMatch { field, .. } => with_segment(path, "field", |p| out.push((field.clone(), p.clone()))),
+        Expr::Call { args, .. } => with_segment(path, "args", |p| {
+            for (i, a) in args.iter().enumerate() {
+                with_segment(p, &i.to_string(), |p| collect_field_paths(a, p, out));
             }
+        }),
+        Expr::If { cond, then, els } => {
+            with_segment(path, "cond", |p| collect_field_paths(cond, p, out));
+            with_segment(path, "then", |p| collect_field_paths(then, p, out));
+            with_segment(path, "els", |p| collect_field_paths(els, p, out));
+        }
+        Expr::Literal(l) => collect_literal_paths(l, path, out),
+        Expr::Field(fr) => out.push((fr.clone(), path.clone())),
+    }
+    path.pop();
+}
+
+fn collect_value_paths(v: &LiteralOrField, path: &mut Vec<String>, out: &mut Vec<(FieldRef, Vec<String>)>) {
+    match v {
+        LiteralOrField::Lit(l) => collect_literal_paths(l, path, out),
+        LiteralOrField::Field(fr) => out.push((fr.clone(), path.clone())),
+    }
+}
+
+fn collect_literal_paths(l: &Literal, path: &mut Vec<String>, out: &mut Vec<(FieldRef, Vec<String>)>) {
+    if let Literal::List(items) = l {
+        for (i, item) in items.iter().enumerate() {
+            with_segment(path, &i.to_string(), |p| collect_value_paths(item, p, out));
+        }
+    }
+}
+
+/// Runs `f` with `segment` appended to `path`.
+fn with_segment(path: &mut Vec<String>, segment: &str, f: impl FnOnce(&mut Vec<String>)) {
+    path.push(segment.to_string());
+    f(path);
+    path.pop();
+}
+
+/// Renames field `from` to `to` (both dotted) everywhere in `expr`: predicate subjects and
+/// values, list entries, call arguments and `Expr::Field` nodes. Indexed uses follow the base
+/// field, so renaming `tags` turns `tags[0]` into `labels[0]`. Returns the number of references
+/// rewritten; with 0, `expr` is unchanged.
+pub fn rename_field(expr: &mut Expr, from: &str, to: &str) -> usize {
+    let to: Vec<String> = to.split('.').map(str::to_string).collect();
+    let mut count = 0;
+    for_each_field_mut(expr, &mut |fr| {
+        if fr.base_dotted() == from {
+            let indices = fr.path.split_off(fr.base_len());
+            fr.path = to.iter().cloned().chain(indices).collect();
+            count += 1;
         }
-        _ => {}
+    });
+    count
+}
+
+/// Removes repeated literal entries from every list literal in `expr`, keeping the first
+/// occurrence of each. Field entries are left alone (their values are only known at runtime),
+/// and `0` / `-0` count as the same entry, as they compare equal.
+pub fn dedupe_lists(expr: &mut Expr) {
+    for_each_literal_mut(expr, &mut dedupe_literal);
+}
+
+fn dedupe_literal(l: &mut Literal) {
+    let Literal::List(items) = l else { return };
+    for item in items.iter_mut() {
+        if let LiteralOrField::Lit(inner @ Literal::List(_)) = item { dedupe_literal(inner); }
+    }
+    // `Literal`'s `Eq`/`Hash` treat `0.0` and `-0.0` (and any two NaNs) as the same entry.
+    // The interior mutability clippy sees is `FieldRef`'s dotted cache, which they ignore.
+    #[allow(clippy::mutable_key_type)]
+    let mut seen: HashSet<&Literal> = HashSet::new();
+    let keep: Vec<bool> = items.iter().map(|item| match item {
+        LiteralOrField::Lit(Literal::List(_)) | LiteralOrField::Field(_) => true,
+        LiteralOrField::Lit(lit) => seen.insert(lit),
+    }).collect();
+    let mut keep = keep.into_iter();
+    items.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Calls `f` on every top-level literal in `expr` (predicate values and `Expr::Literal`);
+/// list elements are reached through their list.
+fn for_each_literal_mut(expr: &mut Expr, f: &mut impl FnMut(&mut Literal)) {
+    match expr {
+        Expr::Not(e) => for_each_literal_mut(e, f),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => { for_each_literal_mut(lhs, f); for_each_literal_mut(rhs, f); }
+        Expr::Compare { value, .. } | Expr::Membership { list: value, .. } | Expr::Contains { value, .. } => {
+            if let LiteralOrField::Lit(l) = value { f(l); }
+        }
+        Expr::Between { low, high, .. } => {
+            if let LiteralOrField::Lit(l) = low { f(l); }
+            if let LiteralOrField::Lit(l) = high { f(l); }
+        }
+        Expr::RegexMatch { .. } | Expr::Field(_) => {}
+        Expr::Call { args, .. } => { for a in args { for_each_literal_mut(a, f); } }
+        Expr::If { cond, then, els } => {
+            for_each_literal_mut(cond, f);
+            for_each_literal_mut(then, f);
+            for_each_literal_mut(els, f);
+        }
+        Expr::Literal(l) => f(l),
+    }
+}
+
+/// Mutable counterpart of `any_field`: calls `f` on every field reference in `expr`.
+fn for_each_field_mut(expr: &mut Expr, f: &mut impl FnMut(&mut FieldRef)) {
+    match expr {
+        Expr::Not(e) => for_each_field_mut(e, f),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => { for_each_field_mut(lhs, f); for_each_field_mut(rhs, f); }
+        Expr::Compare { field, value, .. }
+        | Expr::Membership { field, list: value, .. }
+        | Expr::Contains { field, value, .. } => { f(field); for_each_field_in_value_mut(value, f); }
+        Expr::Between { field, low, high, .. } => {
+            f(field);
+            for_each_field_in_value_mut(low, f);
+            for_each_field_in_value_mut(high, f);
+        }
+        Expr::RegexMatch { field, .. } => f(field),
+        Expr::Call { args, .. } => { for a in args { for_each_field_mut(a, f); } }
+        Expr::If { cond, then, els } => {
+            for_each_field_mut(cond, f);
+            for_each_field_mut(then, f);
+            for_each_field_mut(els, f);
+        }
+        Expr::Literal(l) => for_each_field_in_literal_mut(l, f),
+        Expr::Field(fr) => f(fr),
+    }
+}
+
+fn for_each_field_in_value_mut(v: &mut LiteralOrField, f: &mut impl FnMut(&mut FieldRef)) {
+    match v {
+        LiteralOrField::Lit(l) => for_each_field_in_literal_mut(l, f),
+        LiteralOrField::Field(fr) => f(fr),
+    }
+}
+
+fn for_each_field_in_literal_mut(l: &mut Literal, f: &mut impl FnMut(&mut FieldRef)) {
+    if let Literal::List(items) = l {
+        for it in items { for_each_field_in_value_mut(it, f); }
     }
 }
 
-fn walk(expr: &Expr, d: &mut Dependencies) {
+/// Short-circuiting counterpart of `walk_expr` restricted to field references.
+fn any_field(expr: &Expr, pred: &mut impl FnMut(&FieldRef) -> bool) -> bool {
     match expr {
-        Expr::Not(e) => walk(e, d),
-        Expr::Logical { lhs, rhs, .. } => { walk(lhs, d); walk(rhs, d); }
-        Expr::Compare { field, value, .. } => { add_field(d, field); add_lit_or_field(d, value); }
-        Expr::Membership { field, list, .. } => { add_field(d, field); add_lit_or_field(d, list); }
-        Expr::Contains { field, value, .. } => { add_field(d, field); add_lit_or_field(d, value); }
-        Expr::RegexMatch { field, .. } => { add_field(d, field); }
+        Expr::Not(e) => any_field(e, pred),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => any_field(lhs, pred) || any_field(rhs, pred),
+        Expr::Compare { field, value, .. }
+        | Expr::Membership { field, list: value, .. }
+        | Expr::Contains { field, value, .. } => pred(field) || any_field_in_value(value, pred),
+        Expr::Between { field, low, high, .. } => pred(field) || any_field_in_value(low, pred) || any_field_in_value(high, pred),
+        Expr::RegexMatch { field, .. } => pred(field),
+        Expr::Call { args, .. } => args.iter().any(|a| any_field(a, pred)),
+        Expr::If { cond, then, els } => any_field(cond, pred) || any_field(then, pred) || any_field(els, pred),
+        Expr::Literal(l) => any_field_in_literal(l, pred),
+        Expr::Field(fr) => pred(fr),
+    }
+}
+
+fn any_field_in_value(v: &LiteralOrField, pred: &mut impl FnMut(&FieldRef) -> bool) -> bool {
+    match v {
+        LiteralOrField::Lit(l) => any_field_in_literal(l, pred),
+        LiteralOrField::Field(fr) => pred(fr),
+    }
+}
+
+fn any_field_in_literal(l: &Literal, pred: &mut impl FnMut(&FieldRef) -> bool) -> bool {
+    match l {
+        Literal::List(items) => items.iter().any(|it| any_field_in_value(it, pred)),
+        _ => false,
+    }
+}
+
+/// Nesting depth of `Expr` nodes; a leaf (field, literal, predicate) has depth 1.
+pub fn ast_depth(expr: &Expr) -> usize {
+    1 + match expr {
+        Expr::Not(e) => ast_depth(e),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => ast_depth(lhs).max(ast_depth(rhs)),
+        Expr::Call { args, .. } => args.iter().map(ast_depth).max().unwrap_or(0),
+        Expr::If { cond, then, els } => ast_depth(cond).max(ast_depth(then)).max(ast_depth(els)),
+        _ => 0,
+    }
+}
+
+/// Number of `Expr` nodes in the tree (literal list elements are not counted separately).
+pub fn ast_node_count(expr: &Expr) -> usize {
+    struct Counter(usize);
+    impl Visitor for Counter {
+        fn visit_expr(&mut self, _expr: &Expr) { self.0 += 1; }
+    }
+    let mut c = Counter(0);
+    walk_expr(expr, &mut c);
+    c.0
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ComplexityError {
+    #[error("expression depth {depth} exceeds limit {max}")]
+    TooDeep { depth: usize, max: usize },
+    #[error("expression has {count} nodes, limit is {max}")]
+    TooManyNodes { count: usize, max: usize },
+}
+
+/// Deploy-time guard against pathological inputs: rejects expressions nested deeper than
+/// `max_depth` or with more than `max_nodes` nodes.
+pub fn validate_complexity(expr: &Expr, max_depth: usize, max_nodes: usize) -> Result<(), ComplexityError> {
+    let depth = ast_depth(expr);
+    if depth > max_depth { return Err(ComplexityError::TooDeep { depth, max: max_depth }); }
+    let count = ast_node_count(expr);
+    if count > max_nodes { return Err(ComplexityError::TooManyNodes { count, max: max_nodes }); }
+    Ok(())
+}
+
+/// Read-only AST traversal hooks driven by `walk_expr`.
+///
+/// Every method defaults to a no-op, so implementors override only what they care about.
+/// `walk_expr` handles recursion; hooks must not recurse themselves.
+pub trait Visitor {
+    /// Called for every `Expr` node, before its children (pre-order).
+    fn visit_expr(&mut self, _expr: &Expr) {}
+    /// Called for every field reference: predicate subjects, RHS fields, list entries, `Expr::Field`.
+    fn visit_field(&mut self, _field: &FieldRef) {}
+    /// Called for every `Expr::Call`, before its arguments are walked.
+    fn visit_call(&mut self, _name: &str, _args: &[Expr]) {}
+    /// Called for every literal, including lists (before their elements) and list elements.
+    fn visit_literal(&mut self, _literal: &Literal) {}
+    /// Called for the pattern of every `Expr::RegexMatch`.
+    fn visit_regex(&mut self, _pattern: &str) {}
+}
+
+/// Drives `visitor` over `expr` depth-first, left to right.
+pub fn walk_expr<V: Visitor + ?Sized>(expr: &Expr, visitor: &mut V) {
+    visitor.visit_expr(expr);
+    match expr {
+        Expr::Not(e) => walk_expr(e, visitor),
+        Expr::Logical { lhs, rhs, .. }
+        | Expr::Arithmetic { lhs, rhs, .. }
+        | Expr::CompareExpr { lhs, rhs, .. } => { walk_expr(lhs, visitor); walk_expr(rhs, visitor); }
+        Expr::Compare { field, value, .. } => { visitor.visit_field(field); walk_lit_or_field(value, visitor); }
+        Expr::Membership { field, list, .. } => { visitor.visit_field(field); walk_lit_or_field(list, visitor); }
+        Expr::Between { field, low, high, .. } => {
+            visitor.visit_field(field);
+            walk_lit_or_field(low, visitor);
+            walk_lit_or_field(high, visitor);
+        }
+        Expr::Contains { field, value, .. } => { visitor.visit_field(field); walk_lit_or_field(value, visitor); }
+        Expr::RegexMatch { field, pattern, .. } => { visitor.visit_field(field); visitor.visit_regex(pattern); }
         Expr::Call { name, args } => {
-            d.functions.insert(name.clone());
-            for a in args { walk(a, d); }
+            visitor.visit_call(name, args);
+            for a in args { walk_expr(a, visitor); }
         }
-        Expr::Literal(_) => {}
-        Expr::Field(fr) => add_field(d, fr),
+        Expr::If { cond, then, els } => {
+            walk_expr(cond, visitor);
+            walk_expr(then, visitor);
+            walk_expr(els, visitor);
+        }
+        Expr::Literal(l) => walk_literal(l, visitor),
+        Expr::Field(fr) => visitor.visit_field(fr),
+    }
+}
+
+fn walk_lit_or_field<V: Visitor + ?Sized>(v: &LiteralOrField, visitor: &mut V) {
+    match v {
+        LiteralOrField::Lit(l) => walk_literal(l, visitor),
+        LiteralOrField::Field(fr) => visitor.visit_field(fr),
+    }
+}
+
+fn walk_literal<V: Visitor + ?Sized>(l: &Literal, visitor: &mut V) {
+    visitor.visit_literal(l);
+    if let Literal::List(items) = l {
+        for it in items { walk_lit_or_field(it, visitor); }
     }
 }
@@ -5,6 +5,8 @@
 //! - Evaluate AST (`tiimu-expr-ast::Expr`) against `EvalContext`.
 //! - Short-circuit semantics for `&&` / `||`.
 //! - Pluggable functions via `FunctionRegistry`.
+//! - `Program`: compile once (functions resolved, regexes compiled, long `in` lists hashed), evaluate many times.
+//! - Resource bounds for untrusted input: `EvalOptions::max_depth` and `step_budget`.
 //!
 //! Assumptions:
 //! - Expressions are deploy-time validated, so runtime should not see unknown fields/functions.
@@ -12,12 +14,14 @@
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
-use tiimu_expr_ast::{CompareOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp, MembershipOp};
+use tiimu_expr_ast::{walk_expr, ArithOp, CompareOp, ContainsOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp, MembershipOp, Visitor};
+use tiimu_expr_typecheck::{Dictionary, RegexLimits, Ty};
 
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 /// Runtime value type for optional signature checks in the evaluator.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -52,29 +56,100 @@ pub trait Function: Send + Sync {
 /// Registry for callable functions referenced from expressions.
 ///
 /// Supports "write once, reuse many" by allowing shared functions.
+///
+/// Registries can be layered: lookups check this registry's own functions first, then its
+/// parent (see `with_parent`), so a child shadows any parent function of the same name.
 pub struct FunctionRegistry {
     funcs: HashMap<String, Arc<dyn Function>>,
+    parent: Option<Arc<FunctionRegistry>>,
 }
 
 impl FunctionRegistry {
-    pub fn new() -> Self { Self { funcs: HashMap::new() } }
+    pub fn new() -> Self { Self { funcs: HashMap::new(), parent: None } }
+
+    /// Empty overlay on top of `parent`, e.g. tenant functions over a shared builtin registry.
+    /// Functions registered on the overlay shadow same-named parent functions.
+    pub fn with_parent(parent: Arc<FunctionRegistry>) -> Self {
+        Self { funcs: HashMap::new(), parent: Some(parent) }
+    }
 
     /// Default registry that includes TIIMU builtins.
     pub fn with_builtins() -> Self {
         let mut r = Self::new();
         r.register(Arc::new(LenFn));
+        r.register(Arc::new(AbsFn));
+        r.register(Arc::new(FloorFn));
+        r.register(Arc::new(CeilFn));
+        r.register(Arc::new(RoundFn));
+        r.register(Arc::new(MinFn));
+        r.register(Arc::new(MaxFn));
+        r.register(Arc::new(LowerFn));
+        r.register(Arc::new(UpperFn));
+        r.register(Arc::new(TrimFn));
+        r.register(Arc::new(IntersectFn));
+        r.register(Arc::new(UnionFn));
+        r.register(Arc::new(DifferenceFn));
+        r.register(Arc::new(OverlapsFn));
+        r.register(Arc::new(SubsetFn));
+        r.register(Arc::new(SupersetFn));
+        r.register(Arc::new(ContainsAnyFn));
+        r.register(Arc::new(ContainsAllFn));
+        r.register(Arc::new(IsIntegerFn));
+        r.register(Arc::new(ApproxEqFn));
+        #[cfg(feature = "chrono")]
+        r.register(Arc::new(DatetimeFn));
+        r.register(Arc::new(CoalesceFn));
+        debug_assert!(
+            r.funcs.keys().all(|name| tiimu_dsl::BUILTIN_FUNCTIONS.contains(&name.as_str())),
+            "tiimu_dsl::BUILTIN_FUNCTIONS is missing a builtin",
+        );
         r
     }
 
+    /// Registers `f`, replacing any function of the same name. See `try_register` for a
+    /// checked variant.
     pub fn register(&mut self, f: Arc<dyn Function>) {
         self.funcs.insert(f.name().to_string(), f);
     }
 
+    /// Registers `f` after checking that its name is callable from the DSL (an identifier) and,
+    /// unless `overwrite` is set, that no function of that name is visible yet (parents included).
+    pub fn try_register(&mut self, f: Arc<dyn Function>, overwrite: bool) -> Result<(), RegistryError> {
+        let name = f.name();
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid { return Err(RegistryError::InvalidName(name.to_string())); }
+        if !overwrite && self.get(name).is_some() { return Err(RegistryError::Duplicate(name.to_string())); }
+        self.register(f);
+        Ok(())
+    }
+
+    /// Copies every function visible through `other` (its parents included) into this
+    /// registry; on a name clash `other`'s function replaces the existing one.
+    pub fn merge(&mut self, other: &FunctionRegistry) {
+        if let Some(parent) = &other.parent { self.merge(parent); }
+        for f in other.funcs.values() {
+            self.register(f.clone());
+        }
+    }
+
     pub fn get(&self, name: &str) -> Option<Arc<dyn Function>> {
-        self.funcs.get(name).cloned()
+        match self.funcs.get(name) {
+            Some(f) => Some(f.clone()),
+            None => self.parent.as_ref().and_then(|p| p.get(name)),
+        }
     }
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RegistryError {
+    #[error("invalid function name {0:?}: expected an identifier")]
+    InvalidName(String),
+    #[error("function already registered: {0}")]
+    Duplicate(String),
+}
+
 /// Builtin: len(x) -> number
 /// - len(string) = string length
 /// - len(set) = set length
@@ -88,20 +163,412 @@ impl Function for LenFn {
     }
 
     fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
-        if args.len() != 1 { return Err(EvalError::Type("len expects 1 arg".into())); }
-        match &args[0] {
-            Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
-            Value::Set(v) => Ok(Value::Number(v.len() as f64)),
-            _ => Err(EvalError::Type("len expects string or set".into())),
+        match args {
+            [Value::String(s)] => Ok(Value::Number(s.chars().count() as f64)),
+            [Value::Set(v)] => Ok(Value::Number(v.len() as f64)),
+            [_] => Err(arg_type(self.name(), 0)),
+            _ => Err(arg_count(self.name(), 1, args.len())),
+        }
+    }
+}
+
+/// Builtin: abs(x) -> number
+pub struct AbsFn;
+
+impl Function for AbsFn {
+    fn name(&self) -> &'static str { "abs" }
+    fn signature(&self) -> FunctionSignature { unary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_number(self.name(), args, f64::abs) }
+}
+
+/// Builtin: floor(x) -> number
+pub struct FloorFn;
+
+impl Function for FloorFn {
+    fn name(&self) -> &'static str { "floor" }
+    fn signature(&self) -> FunctionSignature { unary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_number(self.name(), args, f64::floor) }
+}
+
+/// Builtin: ceil(x) -> number
+pub struct CeilFn;
+
+impl Function for CeilFn {
+    fn name(&self) -> &'static str { "ceil" }
+    fn signature(&self) -> FunctionSignature { unary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_number(self.name(), args, f64::ceil) }
+}
+
+/// Builtin: round(x) -> number (halves round away from zero, e.g. round(-2.5) = -3)
+pub struct RoundFn;
+
+impl Function for RoundFn {
+    fn name(&self) -> &'static str { "round" }
+    fn signature(&self) -> FunctionSignature { unary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_number(self.name(), args, f64::round) }
+}
+
+/// Builtin: min(a, b) -> number
+pub struct MinFn;
+
+impl Function for MinFn {
+    fn name(&self) -> &'static str { "min" }
+    fn signature(&self) -> FunctionSignature { binary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { binary_number(self.name(), args, f64::min) }
+}
+
+/// Builtin: max(a, b) -> number
+pub struct MaxFn;
+
+impl Function for MaxFn {
+    fn name(&self) -> &'static str { "max" }
+    fn signature(&self) -> FunctionSignature { binary_number_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { binary_number(self.name(), args, f64::max) }
+}
+
+/// Builtin: lower(s) -> string
+pub struct LowerFn;
+
+impl Function for LowerFn {
+    fn name(&self) -> &'static str { "lower" }
+    fn signature(&self) -> FunctionSignature { unary_string_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_string(self.name(), args, str::to_lowercase) }
+}
+
+/// Builtin: upper(s) -> string
+pub struct UpperFn;
+
+impl Function for UpperFn {
+    fn name(&self) -> &'static str { "upper" }
+    fn signature(&self) -> FunctionSignature { unary_string_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_string(self.name(), args, str::to_uppercase) }
+}
+
+/// Builtin: trim(s) -> string (strips leading/trailing whitespace)
+pub struct TrimFn;
+
+impl Function for TrimFn {
+    fn name(&self) -> &'static str { "trim" }
+    fn signature(&self) -> FunctionSignature { unary_string_signature() }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> { unary_string(self.name(), args, |s| s.trim().to_string()) }
+}
+
+/// Builtin: intersect(a, b) -> set (elements of `a` also in `b`)
+pub struct IntersectFn;
+
+impl Function for IntersectFn {
+    fn name(&self) -> &'static str { "intersect" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Set) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Set(dedup(a.iter().filter(|x| b.contains(x)))))
+    }
+}
+
+/// Builtin: union(a, b) -> set (elements of `a`, then those of `b`)
+pub struct UnionFn;
+
+impl Function for UnionFn {
+    fn name(&self) -> &'static str { "union" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Set) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Set(dedup(a.iter().chain(b))))
+    }
+}
+
+/// Builtin: difference(a, b) -> set (elements of `a` not in `b`)
+pub struct DifferenceFn;
+
+impl Function for DifferenceFn {
+    fn name(&self) -> &'static str { "difference" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Set) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Set(dedup(a.iter().filter(|x| !b.contains(x)))))
+    }
+}
+
+/// Builtin: overlaps(a, b) -> bool (true if the sets share any element)
+pub struct OverlapsFn;
+
+impl Function for OverlapsFn {
+    fn name(&self) -> &'static str { "overlaps" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Bool) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Bool(a.iter().any(|x| b.contains(x))))
+    }
+}
+
+/// Builtin: subset(a, b) -> bool (true if every element of `a` is in `b`; equal sets count)
+pub struct SubsetFn;
+
+impl Function for SubsetFn {
+    fn name(&self) -> &'static str { "subset" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Bool) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Bool(a.iter().all(|x| b.contains(x))))
+    }
+}
+
+/// Builtin: superset(a, b) -> bool (true if every element of `b` is in `a`; equal sets count)
+pub struct SupersetFn;
+
+impl Function for SupersetFn {
+    fn name(&self) -> &'static str { "superset" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Bool) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |a, b| Value::Bool(b.iter().all(|x| a.contains(x))))
+    }
+}
+
+/// Builtin: contains_any(set, list) -> bool (true if any element of `list` is in `set`;
+/// false for an empty `list`)
+pub struct ContainsAnyFn;
+
+impl Function for ContainsAnyFn {
+    fn name(&self) -> &'static str { "contains_any" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Bool) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |set, list| {
+            let found = set_lookup(set, list.len());
+            Value::Bool(list.iter().any(found))
+        })
+    }
+}
+
+/// Builtin: contains_all(set, list) -> bool (true if every element of `list` is in `set`;
+/// true for an empty `list`)
+pub struct ContainsAllFn;
+
+impl Function for ContainsAllFn {
+    fn name(&self) -> &'static str { "contains_all" }
+    fn signature(&self) -> FunctionSignature { binary_set_signature(ValueTy::Bool) }
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        binary_set(self.name(), args, |set, list| {
+            let found = set_lookup(set, list.len());
+            Value::Bool(list.iter().all(found))
+        })
+    }
+}
+
+/// Builtin: is_integer(x) -> bool (true for whole numbers such as `3` or `3.0`)
+pub struct IsIntegerFn;
+
+impl Function for IsIntegerFn {
+    fn name(&self) -> &'static str { "is_integer" }
+
+    fn signature(&self) -> FunctionSignature {
+        FunctionSignature { params: vec![ValueTy::Number], ret: ValueTy::Bool }
+    }
+
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        match args {
+            [Value::Number(x)] => Ok(Value::Bool(x.is_finite() && x.fract() == 0.0)),
+            [_] => Err(arg_type(self.name(), 0)),
+            _ => Err(arg_count(self.name(), 1, args.len())),
         }
     }
 }
 
+/// Builtin: approx_eq(a, b, epsilon) -> bool (true if `|a - b| <= epsilon`)
+pub struct ApproxEqFn;
+
+impl Function for ApproxEqFn {
+    fn name(&self) -> &'static str { "approx_eq" }
+
+    fn signature(&self) -> FunctionSignature {
+        FunctionSignature { params: vec![ValueTy::Number, ValueTy::Number, ValueTy::Number], ret: ValueTy::Bool }
+    }
+
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        match args {
+            [Value::Number(a), Value::Number(b), Value::Number(eps)] => Ok(Value::Bool(approx_eq(*a, *b, *eps))),
+            [_, _, _] => Err(arg_type(self.name(), args.iter().position(|a| !matches!(a, Value::Number(_))).unwrap_or(0))),
+            _ => Err(arg_count(self.name(), 3, args.len())),
+        }
+    }
+}
+
+fn approx_eq(a: f64, b: f64, epsilon: f64) -> bool { a == b || (a - b).abs() <= epsilon }
+
+/// Builtin (feature `chrono`): datetime(s) -> number
+///
+/// Parses an RFC 3339 timestamp (`2024-05-01T12:00:00Z`, `2024-05-01T14:00:00+02:00`) to
+/// seconds since the Unix epoch, fractions included, so timestamps compare numerically
+/// whatever their offset or precision. Malformed input is an `EvalError::Type`.
+#[cfg(feature = "chrono")]
+pub struct DatetimeFn;
+
+#[cfg(feature = "chrono")]
+impl Function for DatetimeFn {
+    fn name(&self) -> &'static str { "datetime" }
+
+    fn signature(&self) -> FunctionSignature {
+        FunctionSignature { params: vec![ValueTy::String], ret: ValueTy::Number }
+    }
+
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        match args {
+            [Value::String(s)] => {
+                let t = chrono::DateTime::parse_from_rfc3339(s)
+                    .map_err(|e| EvalError::Type(format!("datetime: invalid RFC 3339 timestamp {:?}: {}", s, e)))?;
+                Ok(Value::Number(t.timestamp() as f64 + f64::from(t.timestamp_subsec_nanos()) / 1e9))
+            }
+            [_] => Err(arg_type(self.name(), 0)),
+            _ => Err(arg_count(self.name(), 1, args.len())),
+        }
+    }
+}
+
+/// Builtin: coalesce(a, b) -> a unless it is null, else b
+///
+/// Not a special form: both arguments are evaluated (they are pure), so a missing field in
+/// either still fails under `MissingFieldPolicy::Error`.
+pub struct CoalesceFn;
+
+impl Function for CoalesceFn {
+    fn name(&self) -> &'static str { "coalesce" }
+
+    fn signature(&self) -> FunctionSignature {
+        FunctionSignature { params: vec![ValueTy::Any, ValueTy::Any], ret: ValueTy::Any }
+    }
+
+    fn call(&self, args: &[Value], _ctx: &EvalContext) -> Result<Value, EvalError> {
+        match args {
+            [Value::Null, b] => Ok(b.clone()),
+            [a, _] => Ok(a.clone()),
+            _ => Err(arg_count(self.name(), 2, args.len())),
+        }
+    }
+}
+
+fn unary_number_signature() -> FunctionSignature {
+    FunctionSignature { params: vec![ValueTy::Number], ret: ValueTy::Number }
+}
+
+fn binary_number_signature() -> FunctionSignature {
+    FunctionSignature { params: vec![ValueTy::Number, ValueTy::Number], ret: ValueTy::Number }
+}
+
+fn unary_number(name: &str, args: &[Value], f: fn(f64) -> f64) -> Result<Value, EvalError> {
+    match args {
+        [Value::Number(x)] => Ok(Value::Number(f(*x))),
+        [_] => Err(arg_type(name, 0)),
+        _ => Err(arg_count(name, 1, args.len())),
+    }
+}
+
+fn binary_number(name: &str, args: &[Value], f: fn(f64, f64) -> f64) -> Result<Value, EvalError> {
+    match args {
+        [Value::Number(a), Value::Number(b)] => Ok(Value::Number(f(*a, *b))),
+        [Value::Number(_), _] => Err(arg_type(name, 1)),
+        [_, _] => Err(arg_type(name, 0)),
+        _ => Err(arg_count(name, 2, args.len())),
+    }
+}
+
+fn unary_string_signature() -> FunctionSignature {
+    FunctionSignature { params: vec![ValueTy::String], ret: ValueTy::String }
+}
+
+fn unary_string(name: &str, args: &[Value], f: fn(&str) -> String) -> Result<Value, EvalError> {
+    match args {
+        [Value::String(s)] => Ok(Value::String(f(s))),
+        [_] => Err(arg_type(name, 0)),
+        _ => Err(arg_count(name, 1, args.len())),
+    }
+}
+
+fn binary_set_signature(ret: ValueTy) -> FunctionSignature {
+    FunctionSignature { params: vec![ValueTy::Set, ValueTy::Set], ret }
+}
+
+fn binary_set(name: &str, args: &[Value], f: fn(&[Value], &[Value]) -> Value) -> Result<Value, EvalError> {
+    match args {
+        [Value::Set(a), Value::Set(b)] => Ok(f(a, b)),
+        [Value::Set(_), _] => Err(arg_type(name, 1)),
+        [_, _] => Err(arg_type(name, 0)),
+        _ => Err(arg_count(name, 2, args.len())),
+    }
+}
+
+fn arg_count(function: &str, expected: usize, got: usize) -> EvalError {
+    EvalError::ArgCount { function: function.to_string(), expected, got }
+}
+
+fn arg_type(function: &str, index: usize) -> EvalError {
+    EvalError::ArgType { function: function.to_string(), index }
+}
+
+/// Hashable identity of a scalar `Value`, consistent with `==`: `0` and `-0` share a key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ValueKey {
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Null,
+}
+
+impl ValueKey {
+    /// `None` for sets and NaN, which have no such identity.
+    fn of(v: &Value) -> Option<ValueKey> {
+        Some(match v {
+            Value::Bool(b) => ValueKey::Bool(*b),
+            Value::Number(n) if n.is_nan() => return None,
+            Value::Number(n) => ValueKey::Number(if *n == 0.0 { 0 } else { n.to_bits() }),
+            Value::String(s) => ValueKey::String(s.clone()),
+            Value::Null => ValueKey::Null,
+            Value::Set(_) => return None,
+        })
+    }
+}
+
+/// Above this many element comparisons, `set_lookup` hashes the haystack first.
+const HASH_LOOKUP_THRESHOLD: usize = 256;
+
+/// A membership test against `haystack` for `probes` lookups: a linear scan for small inputs,
+/// a `HashSet<ValueKey>` when the scans would cost more than hashing. Unhashable values
+/// (sets, NaN) fall back to scanning.
+fn set_lookup(haystack: &[Value], probes: usize) -> impl Fn(&Value) -> bool + '_ {
+    let keys: Option<HashSet<ValueKey>> = if haystack.len().saturating_mul(probes) > HASH_LOOKUP_THRESHOLD {
+        haystack.iter().map(ValueKey::of).collect()
+    } else {
+        None
+    };
+    move |v| match (&keys, ValueKey::of(v)) {
+        (Some(keys), Some(k)) => keys.contains(&k),
+        _ => haystack.contains(v),
+    }
+}
+
+impl ValueKey {
+    /// The key a scalar literal list entry compares equal to, as `literal_matches` decides it
+    /// (regex literals match strings). `None` for NaN, lists and ranges.
+    fn of_literal(l: &Literal) -> Option<ValueKey> {
+        Some(match l {
+            Literal::Bool(b) => ValueKey::Bool(*b),
+            Literal::Number(n) => return ValueKey::of(&Value::Number(*n)),
+            Literal::String(s) | Literal::Regex(s) => ValueKey::String(s.clone()),
+            Literal::Null => ValueKey::Null,
+            Literal::List(_) | Literal::Range { .. } => return None,
+        })
+    }
+}
+
+/// Collects values, dropping repeats while keeping first-seen order.
+fn dedup<'v>(values: impl Iterator<Item = &'v Value>) -> Vec<Value> {
+    let mut out: Vec<Value> = Vec::new();
+    for v in values {
+        if !out.contains(v) { out.push(v.clone()); }
+    }
+    out
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// Runtime value used by the evaluator.
 ///
 /// Keep this intentionally small for edge execution.
+///
+/// There is no separate integer type: every number is an `f64`, so `3`, `3.0` and JSON `3`
+/// are the same value and `3.0 == 3` is true. Use `as_i64` / `is_integer(x)` to test wholeness.
 pub enum Value {
     Bool(bool),
     Number(f64),
@@ -110,6 +577,17 @@ pub enum Value {
     Set(Vec<Value>),
 }
 
+impl Value {
+    /// The number as an `i64` if it is whole and in range (`3.0` -> `Some(3)`, `3.5` -> `None`).
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            // -2^63 is exact as an f64; 2^63 is the first value past `i64::MAX`.
+            Value::Number(x) if x.fract() == 0.0 && *x >= -9_223_372_036_854_775_808.0 && *x < 9_223_372_036_854_775_808.0 => Some(*x as i64),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Runtime context for evaluation.
 ///
@@ -120,7 +598,224 @@ pub struct EvalContext {
 }
 impl EvalContext {
     pub fn new(values: HashMap<String, Value>) -> Self { Self { values } }
-    pub fn get(&self, field: &FieldRef) -> Option<&Value> { self.values.get(&field.as_dotted()) }
+    pub fn get(&self, field: &FieldRef) -> Option<&Value> { self.values.get(&*field.dotted()) }
+
+    /// The value of `field` (dotted), or `default` if it is absent.
+    pub fn get_or(&self, field: &str, default: Value) -> Value {
+        self.values.get(field).cloned().unwrap_or(default)
+    }
+
+    /// `field` (dotted) as a number; `MissingField` if absent, `Type` if not a number.
+    pub fn get_number(&self, field: &str) -> Result<f64, EvalError> {
+        match self.require(field)? {
+            Value::Number(x) => Ok(*x),
+            other => Err(wrong_field_type(field, "number", other)),
+        }
+    }
+
+    /// `field` (dotted) as a string; errors like `get_number`.
+    pub fn get_string(&self, field: &str) -> Result<&str, EvalError> {
+        match self.require(field)? {
+            Value::String(s) => Ok(s),
+            other => Err(wrong_field_type(field, "string", other)),
+        }
+    }
+
+    /// `field` (dotted) as a bool; errors like `get_number`.
+    pub fn get_bool(&self, field: &str) -> Result<bool, EvalError> {
+        match self.require(field)? {
+            Value::Bool(b) => Ok(*b),
+            other => Err(wrong_field_type(field, "bool", other)),
+        }
+    }
+
+    /// `field` (dotted) as a set's elements; errors like `get_number`.
+    pub fn get_set(&self, field: &str) -> Result<&[Value], EvalError> {
+        match self.require(field)? {
+            Value::Set(items) => Ok(items),
+            other => Err(wrong_field_type(field, "set", other)),
+        }
+    }
+
+    fn require(&self, field: &str) -> Result<&Value, EvalError> {
+        self.values.get(field).ok_or_else(|| EvalError::MissingField(field.to_string()))
+    }
+
+    /// Builds a context from a JSON object whose keys are dotted field refs.
+    ///
+    /// Fails on the first value that cannot be converted (see `Value`'s `TryFrom<serde_json::Value>`).
+    pub fn from_json_map(map: serde_json::Map<String, serde_json::Value>) -> Result<Self, EvalError> {
+        let mut values = HashMap::with_capacity(map.len());
+        for (k, v) in map {
+            let v = Value::try_from(v).map_err(|e| match e {
+                EvalError::Type(msg) => EvalError::Type(format!("field {}: {}", k, msg)),
+                other => other,
+            })?;
+            values.insert(k, v);
+        }
+        Ok(Self { values })
+    }
+}
+
+fn wrong_field_type(field: &str, expected: &str, got: &Value) -> EvalError {
+    EvalError::Type(format!("field {}: expected {}, got {:?}", field, expected, got))
+}
+
+/// Builds an `EvalContext`, checking every value against the dictionary's declared type so
+/// upstream type bugs surface at construction instead of as confusing evaluation errors.
+///
+/// `Value::Null` is accepted for any field (an unset value); set elements are checked individually.
+pub struct EvalContextBuilder<'a> {
+    dict: &'a dyn Dictionary,
+    values: HashMap<String, Value>,
+}
+
+impl<'a> EvalContextBuilder<'a> {
+    pub fn new(dict: &'a dyn Dictionary) -> Self { Self { dict, values: HashMap::new() } }
+
+    /// Adds `field` (dotted), failing with `EvalError::Type` if the field is unknown or `value`
+    /// does not match its type.
+    pub fn insert(mut self, field: &str, value: Value) -> Result<Self, EvalError> {
+        let fr: FieldRef = field.parse().map_err(|e: tiimu_expr_ast::ParseFieldRefError| EvalError::Type(e.to_string()))?;
+        let ty = self.dict.field_type(&fr).ok_or_else(|| EvalError::Type(format!("unknown field {}", field)))?;
+        if !value_has_type(&value, &ty) {
+            return Err(EvalError::Type(format!("field {}: expected {:?}, got {:?}", field, ty, value)));
+        }
+        self.values.insert(field.to_string(), value);
+        Ok(self)
+    }
+
+    pub fn build(self) -> EvalContext { EvalContext::new(self.values) }
+}
+
+fn value_has_type(v: &Value, ty: &Ty) -> bool {
+    match (v, ty) {
+        (_, Ty::Any) | (Value::Null, _) => true,
+        (Value::Bool(_), Ty::Bool) | (Value::Number(_), Ty::Number) | (Value::String(_), Ty::String) => true,
+        (Value::String(s), Ty::Enum { variants, .. }) => variants.contains(s),
+        (Value::Set(items), Ty::Set(inner)) => items.iter().all(|x| value_has_type(x, inner)),
+        _ => false,
+    }
+}
+
+/// JSON scalars map to the matching `Value`; arrays (nested too) become `Value::Set`.
+/// Objects have no `Value` counterpart and are rejected with `EvalError::Type`.
+impl TryFrom<serde_json::Value> for Value {
+    type Error = EvalError;
+
+    fn try_from(v: serde_json::Value) -> Result<Self, Self::Error> {
+        Ok(match v {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(b) => Value::Bool(b),
+            serde_json::Value::Number(n) => Value::Number(n.as_f64().ok_or_else(|| EvalError::Type(format!("number {} is not representable as f64", n)))?),
+            serde_json::Value::String(s) => Value::String(s),
+            serde_json::Value::Array(items) => Value::Set(items.into_iter().map(Value::try_from).collect::<Result<_, _>>()?),
+            serde_json::Value::Object(_) => return Err(EvalError::Type("JSON objects cannot be converted to a Value".into())),
+        })
+    }
+}
+
+/// How the evaluator treats a field that is absent from the `EvalContext`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingFieldPolicy {
+    /// Fail with `EvalError::MissingField` (deploy-time validated contexts).
+    #[default]
+    Error,
+    /// Treat the field as `Value::Null`, e.g. for sparsely populated edge contexts.
+    AsNull,
+}
+
+/// How string operands are compared by `==`, ordering operators, `contains`,
+/// `starts_with` and `ends_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    /// Byte-wise comparison.
+    #[default]
+    CaseSensitive,
+    /// Both sides are lowercased first, so `"Social" == "social"`.
+    CaseInsensitive,
+}
+
+impl Collation {
+    fn fold<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        match self {
+            Collation::CaseSensitive => Cow::Borrowed(s),
+            Collation::CaseInsensitive => Cow::Owned(s.to_lowercase()),
+        }
+    }
+}
+
+/// Knobs for `eval_with_options`. `Default` matches `eval` / `eval_with_registry`.
+///
+/// Keep one `EvalOptions` around for hot expressions: its `regex_cache` is shared by clones,
+/// so each pattern is compiled once across evaluations. Regex size limits are set on the cache
+/// (`RegexCache::with_limits`).
+#[derive(Debug, Clone)]
+pub struct EvalOptions {
+    pub missing_field: MissingFieldPolicy,
+    pub regex_cache: RegexCache,
+    pub string_collation: Collation,
+    /// When set, `==` / `!=` treat numbers within this distance as equal (`0.1 + 0.2 == 0.3`).
+    /// Ordering operators, membership and set equality stay exact.
+    pub number_epsilon: Option<f64>,
+    /// Maximum nesting of evaluated nodes before `EvalError::DepthLimitExceeded`,
+    /// so pathological inputs fail instead of overflowing the stack.
+    pub max_depth: usize,
+    /// Maximum number of nodes evaluated in one evaluation before `EvalError::BudgetExceeded`;
+    /// bounds the work an untrusted expression can cost. `None` is unlimited.
+    pub step_budget: Option<u64>,
+}
+
+/// Default for `EvalOptions::max_depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self {
+            missing_field: MissingFieldPolicy::default(),
+            regex_cache: RegexCache::default(),
+            string_collation: Collation::default(),
+            number_epsilon: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            step_budget: None,
+        }
+    }
+}
+
+/// Thread-safe cache of compiled regexes keyed by pattern string.
+///
+/// Clones share the same underlying cache. Patterns exceeding the cache's `RegexLimits`
+/// fail with `EvalError::Regex`.
+#[derive(Debug, Clone, Default)]
+pub struct RegexCache {
+    compiled: Arc<RwLock<HashMap<String, Arc<Regex>>>>,
+    limits: RegexLimits,
+}
+
+impl RegexCache {
+    pub fn new() -> Self { Self::default() }
+
+    /// An empty cache that compiles patterns under `limits`.
+    pub fn with_limits(limits: RegexLimits) -> Self { Self { compiled: Arc::default(), limits } }
+
+    /// Returns the cached regex for `pattern`, compiling and caching it on first use.
+    pub fn get_or_compile(&self, pattern: &str) -> Result<Arc<Regex>, EvalError> {
+        if let Some(re) = self.compiled.read().ok().and_then(|m| m.get(pattern).cloned()) {
+            return Ok(re);
+        }
+        let re = Arc::new(self.limits.compile(pattern).map_err(|e| EvalError::Regex(e.to_string()))?);
+        if let Ok(mut m) = self.compiled.write() {
+            m.entry(pattern.to_string()).or_insert_with(|| re.clone());
+        }
+        Ok(re)
+    }
+
+    /// Number of distinct patterns compiled so far.
+    pub fn len(&self) -> usize {
+        self.compiled.read().map(|m| m.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
 }
 
 #[derive(Debug, Error)]
@@ -129,8 +824,21 @@ pub enum EvalError {
     MissingField(String),
     #[error("type error: {0}")]
     Type(String),
+    #[error("unknown function: {0}")]
+    UnknownFunction(String),
+    #[error("wrong number of arguments to {function}: expected {expected}, got {got}")]
+    ArgCount { function: String, expected: usize, got: usize },
+    /// `index` is 0-based.
+    #[error("{function}: argument {index} has the wrong type")]
+    ArgType { function: String, index: usize },
     #[error("regex error: {0}")]
     Regex(String),
+    #[error("arithmetic error: {0}")]
+    Arithmetic(String),
+    #[error("expression nesting exceeds depth limit {0}")]
+    DepthLimitExceeded(usize),
+    #[error("evaluation exceeded step budget {0}")]
+    BudgetExceeded(u64),
 }
 
 /// Evaluate using the default builtin function registry.
@@ -145,92 +853,676 @@ pub fn eval(expr: &Expr, ctx: &EvalContext) -> Result<bool, EvalError> {
 ///
 /// Recommended entrypoint for tenant-specific functions.
 pub fn eval_with_registry(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> Result<bool, EvalError> {
+    eval_with_options(expr, ctx, fns, &EvalOptions::default())
+}
 
-    match eval_value(expr, ctx)? {
+/// Evaluate with a caller-provided registry and non-default `EvalOptions`.
+pub fn eval_with_options(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry, opts: &EvalOptions) -> Result<bool, EvalError> {
+    expect_bool(Evaluator::new(ctx, fns, opts).eval_value(expr)?)
+}
+
+fn expect_bool(v: Value) -> Result<bool, EvalError> {
+    match v {
         Value::Bool(b) => Ok(b),
         _ => Err(EvalError::Type("top-level must be bool".into())),
     }
 }
 
-fn eval_value(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> Result<Value, EvalError> {
+/// Field lookup callback for `eval_with_resolver`; `None` means the field is absent.
+pub type FieldResolver<'r> = &'r dyn Fn(&FieldRef) -> Option<Value>;
+
+/// Evaluates `expr`, fetching field values on demand from `resolver` instead of a
+/// pre-populated `EvalContext`.
+///
+/// Each distinct field is resolved at most once per evaluation, and fields in branches skipped
+/// by short-circuiting or an untaken `?:` branch are never resolved. Indexed refs
+/// (`a.tags[0]`) are resolved as written first, then via their base field. Functions receive
+/// an empty `EvalContext`.
+pub fn eval_with_resolver(expr: &Expr, fns: &FunctionRegistry, resolver: FieldResolver<'_>) -> Result<bool, EvalError> {
+    let ctx = EvalContext::new(HashMap::new());
+    let opts = EvalOptions::default();
+    let mut ev = Evaluator::new(&ctx, fns, &opts);
+    ev.resolver = Some(resolver);
+    expect_bool(ev.eval_value(expr)?)
+}
+
+/// Evaluates like `eval_with_registry` and also returns the fields actually read (base dotted
+/// names, as in `Dependencies::fields`), for explanations such as "fired because of A and C".
+///
+/// Fields in branches skipped by short-circuiting or an untaken `?:` branch are not included;
+/// fields tested with `exists` are.
+pub fn eval_with_used_fields(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> Result<(bool, HashSet<String>), EvalError> {
+    let opts = EvalOptions::default();
+    let mut ev = Evaluator::new(ctx, fns, &opts);
+    ev.used = Some(HashSet::new());
+    let b = expect_bool(ev.eval_value(expr)?)?;
+    Ok((b, ev.used.unwrap_or_default()))
+}
+
+/// Three-valued (Kleene) evaluation for previews: a predicate that needs a field missing from
+/// `ctx` is unknown (`None`) instead of an error, and unknowns propagate through `&&`, `||`,
+/// `!` and `?:` (`true || unknown` is `true`, `false && unknown` is `false`, `!unknown` and
+/// `unknown && true` are unknown). Errors other than `MissingField` still fail.
+///
+/// Decisioning should keep using `eval`: deployed expressions never see UNKNOWN.
+pub fn eval_kleene(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> Result<Option<bool>, EvalError> {
+    let opts = EvalOptions::default();
+    kleene(&mut Evaluator::new(ctx, fns, &opts), expr)
+}
+
+/// Subject to the same `max_depth` / `step_budget` accounting as `Evaluator::eval_value`.
+fn kleene(ev: &mut Evaluator, expr: &Expr) -> Result<Option<bool>, EvalError> {
+    ev.enter()?;
+    let result = kleene_node(ev, expr);
+    ev.depth -= 1;
+    result
+}
+
+fn kleene_node(ev: &mut Evaluator, expr: &Expr) -> Result<Option<bool>, EvalError> {
     match expr {
-        Expr::Not(e) => Ok(Value::Bool(!as_bool(&eval_value(e, ctx, fns)?)?)),
-        Expr::Logical { op, lhs, rhs } => match op {
-            LogicalOp::And => {
-                let l = as_bool(&eval_value(lhs, ctx, fns)?)?;
-                if !l { return Ok(Value::Bool(false)); }
-                let r = as_bool(&eval_value(rhs, ctx, fns)?)?;
-                Ok(Value::Bool(l && r))
+        Expr::Not(e) => Ok(kleene(ev, e)?.map(|b| !b)),
+        Expr::Logical { op: LogicalOp::And, lhs, rhs } => {
+            let l = kleene(ev, lhs)?;
+            if l == Some(false) { return Ok(Some(false)); }
+            Ok(match (l, kleene(ev, rhs)?) {
+                (_, Some(false)) => Some(false),
+                (Some(true), r) => r,
+                _ => None,
+            })
+        }
+        Expr::Logical { op: LogicalOp::Or, lhs, rhs } => {
+            let l = kleene(ev, lhs)?;
+            if l == Some(true) { return Ok(Some(true)); }
+            Ok(match (l, kleene(ev, rhs)?) {
+                (_, Some(true)) => Some(true),
+                (Some(false), r) => r,
+                _ => None,
+            })
+        }
+        Expr::Logical { op: LogicalOp::Xor, lhs, rhs } => {
+            let l = kleene(ev, lhs)?;
+            Ok(match (l, kleene(ev, rhs)?) {
+                (Some(l), Some(r)) => Some(l != r),
+                _ => None,
+            })
+        }
+        // An unknown condition still decides the result when both branches agree.
+        Expr::If { cond, then, els } => match kleene(ev, cond)? {
+            Some(true) => kleene(ev, then),
+            Some(false) => kleene(ev, els),
+            None => {
+                let (t, e) = (kleene(ev, then)?, kleene(ev, els)?);
+                Ok(if t == e { t } else { None })
             }
-            LogicalOp::Or => {
-                let l = as_bool(&eval_value(lhs, ctx, fns)?)?;
-                if l { return Ok(Value::Bool(true)); }
-                let r = as_bool(&eval_value(rhs, ctx, fns)?)?;
-                Ok(Value::Bool(l || r))
+        },
+        // Already charged by `kleene`, so evaluate the node itself rather than via `eval_value`.
+        _ => match ev.eval_node(expr) {
+            Ok(v) => expect_bool(v).map(Some),
+            Err(EvalError::MissingField(_)) => Ok(None),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// Evaluates `expr` and returns whatever it produces, without requiring a boolean.
+///
+/// Meant for tooling (e.g. logging `len(signal.tags)`); decisioning should use `eval`,
+/// which rejects non-boolean results.
+pub fn eval_to_value(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> Result<Value, EvalError> {
+    Evaluator::new(ctx, fns, &EvalOptions::default()).eval_value(expr)
+}
+
+/// Evaluates like `eval_with_registry` and also returns a `Trace` of every node's result.
+///
+/// The trace mirrors the AST; branches skipped by `&&`/`||` short-circuiting are recorded
+/// as `TraceOutcome::NotEvaluated`.
+pub fn eval_with_trace(expr: &Expr, ctx: &EvalContext, fns: &FunctionRegistry) -> (Result<bool, EvalError>, Trace) {
+    let opts = EvalOptions::default();
+    let mut ev = Evaluator::new(ctx, fns, &opts);
+    ev.trace = Some(vec![Vec::new()]);
+    let result = ev.eval_value(expr).and_then(expect_bool);
+    let trace = ev.trace.and_then(|mut s| s.pop()).and_then(|mut root| root.pop())
+        .unwrap_or_else(|| Trace::not_evaluated(expr));
+    (result, trace)
+}
+
+/// One evaluated AST node, as recorded by `eval_with_trace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The node rendered as DSL text.
+    pub expr: String,
+    pub outcome: TraceOutcome,
+    /// Traces of the node's sub-expressions, in AST order.
+    pub children: Vec<Trace>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceOutcome {
+    Value(Value),
+    Error(String),
+    /// Skipped by short-circuiting.
+    NotEvaluated,
+}
+
+impl Trace {
+    fn not_evaluated(expr: &Expr) -> Trace {
+        let children = match expr {
+            Expr::Not(e) => vec![Trace::not_evaluated(e)],
+            Expr::Logical { lhs, rhs, .. }
+            | Expr::Arithmetic { lhs, rhs, .. }
+            | Expr::CompareExpr { lhs, rhs, .. } => vec![Trace::not_evaluated(lhs), Trace::not_evaluated(rhs)],
+            Expr::Call { args, .. } => args.iter().map(Trace::not_evaluated).collect(),
+            Expr::If { cond, then, els } => {
+                vec![Trace::not_evaluated(cond), Trace::not_evaluated(then), Trace::not_evaluated(els)]
             }
+            _ => Vec::new(),
+        };
+        Trace { expr: tiimu_dsl::to_dsl(expr), outcome: TraceOutcome::NotEvaluated, children }
+    }
+}
+
+/// Lists every field referenced by `expr` that is absent from `ctx`, sorted and deduplicated.
+///
+/// Unlike `eval`, this does not stop at the first miss and evaluates nothing, which makes it
+/// suitable for deploy-time dry-runs against sample contexts.
+pub fn missing_fields(expr: &Expr, ctx: &EvalContext) -> Vec<String> {
+    let mut missing: Vec<String> = tiimu_expr_ast::extract_dependencies(expr)
+        .fields
+        .into_iter()
+        .filter(|f| !ctx.values.contains_key(f))
+        .collect();
+    missing.sort();
+    missing
+}
+
+/// Partially evaluates `expr` with the fields in `bindings` (dotted path -> value) pinned, then
+/// runs `simplify` on the result.
+///
+/// Bound `Expr::Field` nodes and predicate values become literals, and `exists(f)` of a bound
+/// field becomes `true`. A predicate whose subject is bound is evaluated outright once nothing
+/// in it is unbound; a bound `Compare` subject against an unbound field becomes a
+/// `CompareExpr` instead. Unbound fields are kept, and so is anything whose evaluation fails,
+/// so the error still surfaces at runtime.
+pub fn substitute(expr: &Expr, bindings: &HashMap<String, Value>) -> Expr {
+    let ctx = EvalContext::new(bindings.clone());
+    let fns = FunctionRegistry::new();
+    let opts = EvalOptions::default();
+    let mut ev = Evaluator::new(&ctx, &fns, &opts);
+    tiimu_expr_ast::simplify(&substitute_node(expr, &mut ev))
+}
+
+fn substitute_node(expr: &Expr, ev: &mut Evaluator) -> Expr {
+    let sub = |e: &Expr, ev: &mut Evaluator| Box::new(substitute_node(e, ev));
+    match expr {
+        Expr::Not(e) => Expr::Not(sub(e, ev)),
+        Expr::Logical { op, lhs, rhs } => Expr::Logical { op: op.clone(), lhs: sub(lhs, ev), rhs: sub(rhs, ev) },
+        Expr::Arithmetic { op, lhs, rhs } => Expr::Arithmetic { op: op.clone(), lhs: sub(lhs, ev), rhs: sub(rhs, ev) },
+        Expr::CompareExpr { op, lhs, rhs } => Expr::CompareExpr { op: op.clone(), lhs: sub(lhs, ev), rhs: sub(rhs, ev) },
+        Expr::If { cond, then, els } => Expr::If { cond: sub(cond, ev), then: sub(then, ev), els: sub(els, ev) },
+        Expr::Call { name, args } if is_exists_form(name, args) => match &args[0] {
+            Expr::Field(fr) if ev.ctx.values.contains_key(&*fr.dotted()) => Expr::Literal(Literal::Bool(true)),
+            _ => expr.clone(),
         },
+        Expr::Call { name, args } => Expr::Call { name: name.clone(), args: args.iter().map(|a| substitute_node(a, ev)).collect() },
+        Expr::Field(fr) => match bound_value(fr, ev) {
+            Some(v) => Expr::Literal(value_to_literal(v)),
+            None => expr.clone(),
+        },
+        Expr::Literal(l) => Expr::Literal(substitute_literal(l, ev)),
         Expr::Compare { field, op, value } => {
-            let fv = ctx.get(field).ok_or_else(|| EvalError::MissingField(field.as_dotted()))?.clone();
-            let vv = eval_lit_or_field(value, ctx, fns)?;
-            Ok(Value::Bool(compare(op, &fv, &vv)?))
+            let value = substitute_value(value, ev);
+            match (bound_value(field, ev), &value) {
+                (Some(v), LiteralOrField::Field(rhs)) => Expr::CompareExpr {
+                    op: op.clone(),
+                    lhs: Box::new(Expr::Literal(value_to_literal(v))),
+                    rhs: Box::new(Expr::Field(rhs.clone())),
+                },
+                _ => fold_predicate(Expr::Compare { field: field.clone(), op: op.clone(), value }, ev),
+            }
         }
         Expr::Membership { field, op, list } => {
-            let fv = ctx.get(field).ok_or_else(|| EvalError::MissingField(field.as_dotted()))?.clone();
-            let target = eval_lit_or_field(list, ctx, fns)?;
-            Ok(Value::Bool(membership(op, &fv, &target)?))
-        }
-        Expr::Contains { field, value, .. } => {
-            let fv = ctx.get(field).ok_or_else(|| EvalError::MissingField(field.as_dotted()))?.clone();
-            let vv = eval_lit_or_field(value, ctx, fns)?;
-            Ok(Value::Bool(contains(&fv, &vv)?))
+            fold_predicate(Expr::Membership { field: field.clone(), op: op.clone(), list: substitute_value(list, ev) }, ev)
         }
-        Expr::RegexMatch { field, pattern } => {
-            let fv = ctx.get(field).ok_or_else(|| EvalError::MissingField(field.as_dotted()))?.clone();
-            let s = as_string(&fv)?;
-            let re = Regex::new(pattern).map_err(|e| EvalError::Regex(e.to_string()))?;
-            Ok(Value::Bool(re.is_match(&s)))
-        }
-        
-Expr::Call { name, args } => {
-    // Special-form: exists(field_ref) -> bool
-    if name == "exists" && args.len() == 1 {
-        if let Expr::Field(fr) = &args[0] {
-            return Ok(Value::Bool(ctx.values.contains_key(&fr.as_dotted())));
+        Expr::Between { field, low, high, inclusive } => fold_predicate(Expr::Between {
+            field: field.clone(),
+            low: substitute_value(low, ev),
+            high: substitute_value(high, ev),
+            inclusive: *inclusive,
+        }, ev),
+        Expr::Contains { field, op, value } => {
+            fold_predicate(Expr::Contains { field: field.clone(), op: op.clone(), value: substitute_value(value, ev) }, ev)
         }
+        Expr::RegexMatch { .. } => fold_predicate(expr.clone(), ev),
     }
+}
 
-    // Evaluate args (pure expressions)
-    let mut argv = Vec::with_capacity(args.len());
-    for a in args {
-        argv.push(eval_value(a, ctx, fns)?);
+/// Evaluates a predicate whose fields are all bound; anything else is returned as-is.
+fn fold_predicate(pred: Expr, ev: &mut Evaluator) -> Expr {
+    let all_bound = !tiimu_expr_ast::extract_dependencies(&pred).fields.iter().any(|f| !ev.ctx.values.contains_key(f));
+    if !all_bound { return pred; }
+    match ev.eval_value(&pred) {
+        Ok(v) => Expr::Literal(value_to_literal(v)),
+        Err(_) => pred,
     }
+}
 
-    let f = fns.get(name).ok_or_else(|| EvalError::Type(format!("unknown function {}", name)))?;
-    f.call(&argv, ctx)
+fn substitute_value(v: &LiteralOrField, ev: &mut Evaluator) -> LiteralOrField {
+    match v {
+        LiteralOrField::Lit(l) => LiteralOrField::Lit(substitute_literal(l, ev)),
+        LiteralOrField::Field(fr) => match bound_value(fr, ev) {
+            Some(v) => LiteralOrField::Lit(value_to_literal(v)),
+            None => v.clone(),
+        },
+    }
 }
-Expr::Literal(l) => Ok(literal_to_value(l)),
-        Expr::Field(fr) => Ok(ctx.get(fr).ok_or_else(|| EvalError::MissingField(fr.as_dotted()))?.clone()),
+
+fn substitute_literal(l: &Literal, ev: &mut Evaluator) -> Literal {
+    match l {
+        Literal::List(items) => Literal::List(items.iter().map(|it| substitute_value(it, ev)).collect()),
+        _ => l.clone(),
     }
 }
 
-fn eval_lit_or_field(v: &LiteralOrField, ctx: &EvalContext, _fns: &FunctionRegistry) -> Result<Value, EvalError> {
+/// The value of `fr` if its base field is bound and any indices resolve.
+fn bound_value(fr: &FieldRef, ev: &mut Evaluator) -> Option<Value> {
+    if !ev.ctx.values.contains_key(&fr.base_dotted()) { return None; }
+    ev.lookup(fr).ok()
+}
+
+fn value_to_literal(v: Value) -> Literal {
     match v {
-        LiteralOrField::Lit(l) => Ok(literal_to_value(l)),
-        LiteralOrField::Field(fr) => Ok(ctx.get(fr).ok_or_else(|| EvalError::MissingField(fr.as_dotted()))?.clone()),
+        Value::Bool(b) => Literal::Bool(b),
+        Value::Number(n) => Literal::Number(n),
+        Value::String(s) => Literal::String(s),
+        Value::Null => Literal::Null,
+        Value::Set(items) => Literal::List(items.into_iter().map(|v| LiteralOrField::Lit(value_to_literal(v))).collect()),
     }
 }
 
-fn literal_to_value(l: &Literal) -> Value {
-    match l {
-        Literal::Bool(b) => Value::Bool(*b),
-        Literal::Number(n) => Value::Number(*n),
-        Literal::String(s) => Value::String(s.clone()),
-        Literal::Null => Value::Null,
-        Literal::Regex(s) => Value::String(s.clone()),
-        Literal::List(items) => Value::Set(items.iter().map(|x| match x {
-            LiteralOrField::Lit(li) => literal_to_value(li),
-            LiteralOrField::Field(fr) => Value::String(fr.as_dotted()),
-        }).collect()),
+/// An expression validated and prepared once for repeated evaluation.
+///
+/// `compile` resolves every function reference against the registry (unknown functions fail
+/// here rather than at eval time) and pre-compiles every regex pattern, so `eval` pays
+/// neither cost per call. Membership tests against long literal lists are hashed once here,
+/// making each lookup O(1) instead of a scan.
+#[derive(Clone)]
+pub struct Program {
+    /// Shared so that clones keep the node addresses `member_sets` is keyed by.
+    expr: Arc<Expr>,
+    fns: FunctionRegistry,
+    opts: EvalOptions,
+    member_sets: Arc<MemberSets>,
+}
+
+/// Precomputed membership sets, keyed by the address of their `Expr::Membership` node.
+type MemberSets = HashMap<usize, HashSet<ValueKey>>;
+
+/// Literal lists longer than this get a precomputed set in `Program`.
+const MEMBER_SET_MIN_LEN: usize = 16;
+
+fn node_id(expr: &Expr) -> usize {
+    expr as *const Expr as usize
+}
+
+impl Program {
+    pub fn compile(expr: &Expr, fns: &FunctionRegistry) -> Result<Program, EvalError> {
+        Self::compile_with_options(expr, fns, EvalOptions::default())
+    }
+
+    /// Like `compile`, but evaluates with `opts` (its regex cache is populated here).
+    pub fn compile_with_options(expr: &Expr, fns: &FunctionRegistry, opts: EvalOptions) -> Result<Program, EvalError> {
+        let expr = Arc::new(expr.clone());
+        let mut prep = ProgramPrep { fns, opts: &opts, resolved: FunctionRegistry::new(), member_sets: HashMap::new(), error: None };
+        walk_expr(&expr, &mut prep);
+        let ProgramPrep { resolved, member_sets, error, .. } = prep;
+        if let Some(e) = error { return Err(e); }
+        Ok(Program { expr, fns: resolved, opts, member_sets: Arc::new(member_sets) })
+    }
+
+    pub fn expr(&self) -> &Expr { &self.expr }
+
+    pub fn eval(&self, ctx: &EvalContext) -> Result<bool, EvalError> {
+        let mut ev = Evaluator::new(ctx, &self.fns, &self.opts);
+        ev.member_sets = Some(&self.member_sets);
+        expect_bool(ev.eval_value(&self.expr)?)
+    }
+
+    /// Evaluates each context in turn, reusing the compiled regexes and resolved functions.
+    /// Results are in input order; an error in one context does not affect the others.
+    pub fn eval_batch(&self, contexts: impl IntoIterator<Item = EvalContext>) -> Vec<Result<bool, EvalError>> {
+        contexts.into_iter().map(|ctx| self.eval(&ctx)).collect()
+    }
+}
+
+/// Collects the functions a program needs, warms its regex cache and hashes long membership
+/// lists; stops at the first error.
+struct ProgramPrep<'a> {
+    fns: &'a FunctionRegistry,
+    opts: &'a EvalOptions,
+    resolved: FunctionRegistry,
+    member_sets: MemberSets,
+    error: Option<EvalError>,
+}
+
+impl Visitor for ProgramPrep<'_> {
+    fn visit_call(&mut self, name: &str, args: &[Expr]) {
+        if self.error.is_some() || is_exists_form(name, args) { return; }
+        match self.fns.get(name) {
+            Some(f) => self.resolved.register(f),
+            None => self.error = Some(EvalError::UnknownFunction(name.to_string())),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if self.error.is_some() { return; }
+        match expr {
+            Expr::RegexMatch { pattern, anchored, .. } => {
+                if let Err(e) = self.opts.regex_cache.get_or_compile(&effective_pattern(pattern, *anchored)) {
+                    self.error = Some(e);
+                }
+            }
+            // Only lists of hashable scalar literals; anything else keeps the scan.
+            Expr::Membership { list: LiteralOrField::Lit(Literal::List(items)), .. } if items.len() > MEMBER_SET_MIN_LEN => {
+                let keys: Option<HashSet<ValueKey>> = items.iter()
+                    .map(|item| match item { LiteralOrField::Lit(l) => ValueKey::of_literal(l), LiteralOrField::Field(_) => None })
+                    .collect();
+                if let Some(keys) = keys { self.member_sets.insert(node_id(expr), keys); }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The pattern actually compiled for a `RegexMatch`: anchored matches must cover the whole string.
+fn effective_pattern(pattern: &str, anchored: bool) -> Cow<'_, str> {
+    if anchored { Cow::Owned(format!("^(?:{})$", pattern)) } else { Cow::Borrowed(pattern) }
+}
+
+/// `exists(field)` is evaluated as a special form and never looked up in the registry.
+fn is_exists_form(name: &str, args: &[Expr]) -> bool {
+    name == "exists" && matches!(args, [Expr::Field(_)])
+}
+
+/// Per-evaluation state: the inputs plus an optional trace being recorded.
+struct Evaluator<'a> {
+    ctx: &'a EvalContext,
+    fns: &'a FunctionRegistry,
+    opts: &'a EvalOptions,
+    /// Stack of child lists for the nodes currently being evaluated; `None` when not tracing.
+    trace: Option<Vec<Vec<Trace>>>,
+    /// Number of `eval_value` frames currently on the stack.
+    depth: usize,
+    /// Nodes evaluated so far, checked against `EvalOptions::step_budget`.
+    steps: u64,
+    /// Fallback for fields absent from `ctx` (see `eval_with_resolver`), with its results
+    /// memoized by dotted name.
+    resolver: Option<FieldResolver<'a>>,
+    resolved: HashMap<String, Option<Value>>,
+    /// Base dotted names of the fields read so far; `None` when not recording.
+    used: Option<HashSet<String>>,
+    /// Precomputed membership sets of the `Program` being evaluated.
+    member_sets: Option<&'a MemberSets>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(ctx: &'a EvalContext, fns: &'a FunctionRegistry, opts: &'a EvalOptions) -> Self {
+        Self { ctx, fns, opts, trace: None, depth: 0, steps: 0, resolver: None, resolved: HashMap::new(), used: None, member_sets: None }
+    }
+
+    fn eval_value(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        self.enter()?;
+        let result = if self.trace.is_some() { self.eval_traced(expr) } else { self.eval_node(expr) };
+        self.depth -= 1;
+        result
+    }
+
+    /// Charges one node against `max_depth` and `step_budget`; on success the caller must
+    /// decrement `depth` once the node is done.
+    fn enter(&mut self) -> Result<(), EvalError> {
+        if self.depth >= self.opts.max_depth {
+            return Err(EvalError::DepthLimitExceeded(self.opts.max_depth));
+        }
+        self.steps += 1;
+        if let Some(budget) = self.opts.step_budget.filter(|b| self.steps > *b) {
+            return Err(EvalError::BudgetExceeded(budget));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn eval_traced(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        self.trace.as_mut().expect("trace stack").push(Vec::new());
+        let result = self.eval_node(expr);
+        let stack = self.trace.as_mut().expect("trace stack");
+        let children = stack.pop().unwrap_or_default();
+        let outcome = match &result {
+            Ok(v) => TraceOutcome::Value(v.clone()),
+            Err(e) => TraceOutcome::Error(e.to_string()),
+        };
+        if let Some(parent) = stack.last_mut() {
+            parent.push(Trace { expr: tiimu_dsl::to_dsl(expr), outcome, children });
+        }
+        result
+    }
+
+    /// Records `expr` (and its subtree) as skipped by short-circuiting.
+    fn skip(&mut self, expr: &Expr) {
+        if let Some(parent) = self.trace.as_mut().and_then(|s| s.last_mut()) {
+            parent.push(Trace::not_evaluated(expr));
+        }
+    }
+
+    fn eval_node(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        match expr {
+            Expr::Not(e) => Ok(Value::Bool(!as_bool(&self.eval_value(e)?)?)),
+            Expr::Logical { op, lhs, rhs } => match op {
+                LogicalOp::And => {
+                    let l = as_bool(&self.eval_value(lhs)?)?;
+                    if !l { self.skip(rhs); return Ok(Value::Bool(false)); }
+                    let r = as_bool(&self.eval_value(rhs)?)?;
+                    Ok(Value::Bool(l && r))
+                }
+                LogicalOp::Or => {
+                    let l = as_bool(&self.eval_value(lhs)?)?;
+                    if l { self.skip(rhs); return Ok(Value::Bool(true)); }
+                    let r = as_bool(&self.eval_value(rhs)?)?;
+                    Ok(Value::Bool(l || r))
+                }
+                // Never short-circuits: the result depends on both sides.
+                LogicalOp::Xor => {
+                    let l = as_bool(&self.eval_value(lhs)?)?;
+                    let r = as_bool(&self.eval_value(rhs)?)?;
+                    Ok(Value::Bool(l != r))
+                }
+            },
+            Expr::Arithmetic { op, lhs, rhs } => {
+                let l = self.eval_value(lhs)?;
+                let r = self.eval_value(rhs)?;
+                arithmetic(op, &l, &r)
+            }
+            Expr::CompareExpr { op, lhs, rhs } => {
+                let l = self.eval_value(lhs)?;
+                let r = self.eval_value(rhs)?;
+                Ok(Value::Bool(compare(op, &l, &r, self.opts)?))
+            }
+            Expr::Call { name, args } => self.eval_call(name, args),
+            // Trace children stay in `cond`, `then`, `els` order whichever branch is taken.
+            Expr::If { cond, then, els } => {
+                if as_bool(&self.eval_value(cond)?)? {
+                    let v = self.eval_value(then)?;
+                    self.skip(els);
+                    Ok(v)
+                } else {
+                    self.skip(then);
+                    self.eval_value(els)
+                }
+            }
+            Expr::Literal(l) => self.eval_literal(l),
+            Expr::Field(fr) => self.lookup(fr),
+            Expr::Compare { .. } | Expr::Membership { .. } | Expr::Between { .. }
+            | Expr::Contains { .. } | Expr::RegexMatch { .. } => self.eval_predicate(expr),
+        }
+    }
+
+    /// Field-subject predicates. Kept out of `eval_node` so the recursive path's stack
+    /// frames stay small, which is what makes `max_depth` a meaningful bound.
+    fn eval_predicate(&mut self, expr: &Expr) -> Result<Value, EvalError> {
+        match expr {
+            Expr::Compare { field, op, value } => {
+                let fv = self.lookup(field)?;
+                let vv = self.eval_lit_or_field(value)?;
+                Ok(Value::Bool(compare(op, &fv, &vv, self.opts)?))
+            }
+            Expr::Membership { field, op, list: LiteralOrField::Lit(Literal::Range { start, end, inclusive }) } => {
+                let fv = self.lookup(field)?;
+                Ok(Value::Bool(range_membership(op, &fv, *start, *end, *inclusive)?))
+            }
+            // Scanned in place rather than materialized as a `Value::Set`, stopping at the first hit.
+            Expr::Membership { field, op, list: LiteralOrField::Lit(Literal::List(items)) } => {
+                let fv = self.lookup(field)?;
+                let precomputed = self.member_sets.and_then(|sets| sets.get(&node_id(expr)));
+                if let (Some(keys), Some(key)) = (precomputed, ValueKey::of(&fv)) {
+                    let contained = keys.contains(&key);
+                    return Ok(Value::Bool(match op { MembershipOp::In => contained, MembershipOp::NotIn => !contained }));
+                }
+                let mut contained = false;
+                for item in items {
+                    let hit = match item {
+                        LiteralOrField::Lit(l) => match literal_matches(&fv, l) {
+                            Some(hit) => hit,
+                            None => self.eval_lit_or_field(item)? == fv,
+                        },
+                        LiteralOrField::Field(_) => self.eval_lit_or_field(item)? == fv,
+                    };
+                    if hit { contained = true; break; }
+                }
+                Ok(Value::Bool(match op { MembershipOp::In => contained, MembershipOp::NotIn => !contained }))
+            }
+            Expr::Membership { field, op, list } => {
+                let fv = self.lookup(field)?;
+                let target = self.eval_lit_or_field(list)?;
+                match (list, &target) {
+                    (LiteralOrField::Field(t), Value::String(_)) => Err(EvalError::Type(format!(
+                        "membership target {} is a string, not a set (use `{} contains {}` for substrings)",
+                        t.as_dotted(), t.as_dotted(), field.as_dotted()))),
+                    (LiteralOrField::Field(t), Value::Bool(_) | Value::Number(_) | Value::Null) => Err(EvalError::Type(format!(
+                        "membership target {} must be a set", t.as_dotted()))),
+                    _ => Ok(Value::Bool(membership(op, &fv, &target)?)),
+                }
+            }
+            Expr::Between { field, low, high, inclusive } => {
+                let fv = self.lookup(field)?;
+                let lo = self.eval_lit_or_field(low)?;
+                let hi = self.eval_lit_or_field(high)?;
+                let (lo_op, hi_op) = if *inclusive { (CompareOp::Ge, CompareOp::Le) } else { (CompareOp::Gt, CompareOp::Lt) };
+                Ok(Value::Bool(compare(&lo_op, &fv, &lo, self.opts)? && compare(&hi_op, &fv, &hi, self.opts)?))
+            }
+            Expr::Contains { field, op, value } => {
+                let fv = self.lookup(field)?;
+                let vv = self.eval_lit_or_field(value)?;
+                Ok(Value::Bool(contains(op, &fv, &vv, self.opts.string_collation)?))
+            }
+            Expr::RegexMatch { field, pattern, anchored } => {
+                let fv = self.lookup(field)?;
+                let s = as_string(&fv)?;
+                let re = self.opts.regex_cache.get_or_compile(&effective_pattern(pattern, *anchored))?;
+                Ok(Value::Bool(re.is_match(&s)))
+            }
+            _ => unreachable!("eval_predicate called on a non-predicate node"),
+        }
+    }
+
+    fn eval_call(&mut self, name: &str, args: &[Expr]) -> Result<Value, EvalError> {
+        // Special-form: exists(field_ref) -> bool
+        if is_exists_form(name, args) {
+            if let Expr::Field(fr) = &args[0] {
+                self.record_use(fr);
+                return Ok(Value::Bool(self.ctx.values.contains_key(&*fr.dotted()) || self.resolve(fr).is_some()));
+            }
+        }
+
+        // Evaluate args (pure expressions)
+        let mut argv = Vec::with_capacity(args.len());
+        for a in args {
+            argv.push(self.eval_value(a)?);
+        }
+
+        let f = self.fns.get(name).ok_or_else(|| EvalError::UnknownFunction(name.to_string()))?;
+        f.call(&argv, self.ctx)
+    }
+
+    fn eval_lit_or_field(&mut self, v: &LiteralOrField) -> Result<Value, EvalError> {
+        match v {
+            LiteralOrField::Lit(l) => self.eval_literal(l),
+            LiteralOrField::Field(fr) => self.lookup(fr),
+        }
+    }
+
+    /// Converts a literal to a runtime value; field references inside lists are resolved
+    /// against the context (so `x in [a.b, "c"]` compares against the value of `a.b`).
+    fn eval_literal(&mut self, l: &Literal) -> Result<Value, EvalError> {
+        Ok(match l {
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::Number(n) => Value::Number(*n),
+            Literal::String(s) => Value::String(s.clone()),
+            Literal::Null => Value::Null,
+            Literal::Regex(s) => Value::String(s.clone()),
+            Literal::List(items) => Value::Set(items.iter().map(|x| self.eval_lit_or_field(x)).collect::<Result<_, _>>()?),
+            Literal::Range { .. } => return Err(EvalError::Type("range literal is only valid as a membership target".into())),
+        })
+    }
+
+    fn lookup(&mut self, field: &FieldRef) -> Result<Value, EvalError> {
+        self.record_use(field);
+        if let Some(v) = self.ctx.get(field) {
+            return Ok(v.clone());
+        }
+        if let Some(v) = self.resolve(field) {
+            return Ok(v);
+        }
+        let indices = field.indices();
+        if indices.is_empty() {
+            return self.missing(field.as_dotted());
+        }
+        let resolved_base;
+        let mut v = match self.ctx.values.get(&field.base_dotted()) {
+            Some(v) => v,
+            None => match self.resolve(&field.base()) {
+                Some(base) => { resolved_base = base; &resolved_base }
+                None => return self.missing(field.base_dotted()),
+            },
+        };
+        for i in indices {
+            v = match v {
+                Value::Set(items) => items.get(i).ok_or_else(|| {
+                    EvalError::Type(format!("index {} out of bounds for {} (len {})", i, field.as_dotted(), items.len()))
+                })?,
+                _ => return Err(EvalError::Type(format!("cannot index into non-set value at {}", field.as_dotted()))),
+            };
+        }
+        Ok(v.clone())
+    }
+
+    fn record_use(&mut self, field: &FieldRef) {
+        if let Some(used) = &mut self.used { used.insert(field.base_dotted()); }
+    }
+
+    fn resolve(&mut self, field: &FieldRef) -> Option<Value> {
+        let resolver = self.resolver?;
+        let key = field.as_dotted();
+        if let Some(v) = self.resolved.get(&key) {
+            return v.clone();
+        }
+        let v = resolver(field);
+        self.resolved.insert(key, v.clone());
+        v
+    }
+
+    fn missing(&self, field: String) -> Result<Value, EvalError> {
+        match self.opts.missing_field {
+            MissingFieldPolicy::AsNull => Ok(Value::Null),
+            MissingFieldPolicy::Error => Err(EvalError::MissingField(field)),
+        }
     }
 }
 
@@ -241,26 +1533,69 @@ fn as_string(v: &Value) -> Result<String, EvalError> {
     match v { Value::String(s) => Ok(s.clone()), _ => Err(EvalError::Type("expected string".into())) }
 }
 
-fn compare(op: &CompareOp, a: &Value, b: &Value) -> Result<bool, EvalError> {
+/// Compares two values. NaN on either side is an error rather than IEEE's silent
+/// `false` (or `true` for `!=`), so a bad context value can't quietly flip a decision.
+fn compare(op: &CompareOp, a: &Value, b: &Value, opts: &EvalOptions) -> Result<bool, EvalError> {
     match (a, b) {
-        (Value::Number(x), Value::Number(y)) => Ok(match op {
-            CompareOp::Eq => x == y, CompareOp::Ne => x != y,
-            CompareOp::Lt => x < y, CompareOp::Le => x <= y,
-            CompareOp::Gt => x > y, CompareOp::Ge => x >= y,
-        }),
-        (Value::String(x), Value::String(y)) => Ok(match op {
-            CompareOp::Eq => x == y, CompareOp::Ne => x != y,
-            CompareOp::Lt => x < y, CompareOp::Le => x <= y,
-            CompareOp::Gt => x > y, CompareOp::Ge => x >= y,
+        (Value::Number(x), Value::Number(y)) if x.is_nan() || y.is_nan() => Err(EvalError::Type("NaN in comparison".into())),
+        (Value::Number(x), Value::Number(y)) => Ok(match (op, opts.number_epsilon) {
+            (CompareOp::Eq, Some(eps)) => approx_eq(*x, *y, eps),
+            (CompareOp::Ne, Some(eps)) => !approx_eq(*x, *y, eps),
+            (CompareOp::Eq, None) => x == y, (CompareOp::Ne, None) => x != y,
+            (CompareOp::Lt, _) => x < y, (CompareOp::Le, _) => x <= y,
+            (CompareOp::Gt, _) => x > y, (CompareOp::Ge, _) => x >= y,
         }),
+        (Value::String(x), Value::String(y)) => {
+            let (x, y) = (opts.string_collation.fold(x), opts.string_collation.fold(y));
+            Ok(match op {
+                CompareOp::Eq => x == y, CompareOp::Ne => x != y,
+                CompareOp::Lt => x < y, CompareOp::Le => x <= y,
+                CompareOp::Gt => x > y, CompareOp::Ge => x >= y,
+            })
+        }
         (Value::Bool(x), Value::Bool(y)) => Ok(match op {
             CompareOp::Eq => x == y, CompareOp::Ne => x != y,
             _ => return Err(EvalError::Type("ordering not supported for bool".into())),
         }),
+        // Set semantics: order and repeated elements are ignored, so `[a, a, b] == [b, a]`.
+        (Value::Set(x), Value::Set(y)) => {
+            let equal = x.iter().all(|v| y.contains(v)) && y.iter().all(|v| x.contains(v));
+            Ok(match op {
+                CompareOp::Eq => equal, CompareOp::Ne => !equal,
+                _ => return Err(EvalError::Type("ordering not supported for sets".into())),
+            })
+        }
+        // Mirrors the typechecker: anything may be tested for (in)equality with null, never ordered.
+        (Value::Null, _) | (_, Value::Null) => {
+            let both_null = matches!((a, b), (Value::Null, Value::Null));
+            Ok(match op {
+                CompareOp::Eq => both_null, CompareOp::Ne => !both_null,
+                _ => return Err(EvalError::Type("ordering not supported for null".into())),
+            })
+        }
+        (Value::Set(_), _) | (_, Value::Set(_)) if matches!(op, CompareOp::Eq | CompareOp::Ne) => Err(EvalError::Type(
+            "cannot compare a set with a scalar; use `in` / `not in` to test membership in a list".into())),
         _ => Err(EvalError::Type("incompatible types for compare".into())),
     }
 }
 
+/// Numeric arithmetic, plus `+` as concatenation when both sides are strings.
+fn arithmetic(op: &ArithOp, a: &Value, b: &Value) -> Result<Value, EvalError> {
+    let (x, y) = match (a, b) {
+        (Value::Number(x), Value::Number(y)) => (*x, *y),
+        (Value::String(x), Value::String(y)) if *op == ArithOp::Add => return Ok(Value::String(format!("{}{}", x, y))),
+        _ => return Err(EvalError::Type("arithmetic expects numbers (or two strings for `+`)".into())),
+    };
+    Ok(Value::Number(match op {
+        ArithOp::Add => x + y,
+        ArithOp::Sub => x - y,
+        ArithOp::Mul => x * y,
+        ArithOp::Div | ArithOp::Mod if y == 0.0 => return Err(EvalError::Arithmetic("division by zero".into())),
+        ArithOp::Div => x / y,
+        ArithOp::Mod => x % y,
+    }))
+}
+
 fn membership(op: &MembershipOp, item: &Value, target: &Value) -> Result<bool, EvalError> {
     let contained = match target {
         Value::Set(items) => items.iter().any(|v| v == item),
@@ -269,10 +1604,40 @@ fn membership(op: &MembershipOp, item: &Value, target: &Value) -> Result<bool, E
     Ok(match op { MembershipOp::In => contained, MembershipOp::NotIn => !contained })
 }
 
-fn contains(container: &Value, needle: &Value) -> Result<bool, EvalError> {
-    match (container, needle) {
-        (Value::String(s), Value::String(sub)) => Ok(s.contains(sub)),
-        (Value::Set(items), v) => Ok(items.iter().any(|x| x == v)),
-        _ => Err(EvalError::Type("contains expects string/string or set/T".into())),
+/// `v == l` for scalar literals without converting `l` to a `Value`; `None` for lists and ranges.
+fn literal_matches(v: &Value, l: &Literal) -> Option<bool> {
+    Some(match (v, l) {
+        (_, Literal::List(_) | Literal::Range { .. }) => return None,
+        (Value::Bool(a), Literal::Bool(b)) => a == b,
+        (Value::Number(a), Literal::Number(b)) => a == b,
+        (Value::String(a), Literal::String(b) | Literal::Regex(b)) => a == b,
+        (Value::Null, Literal::Null) => true,
+        _ => false,
+    })
+}
+
+/// `x in start..end` without materializing the range: true for integral `x` within bounds.
+fn range_membership(op: &MembershipOp, item: &Value, start: i64, end: i64, inclusive: bool) -> Result<bool, EvalError> {
+    let Value::Number(x) = item else { return Err(EvalError::Type("range membership expects a number".into())) };
+    let (x, start, end) = (*x, start as f64, end as f64);
+    let contained = x.fract() == 0.0 && x >= start && if inclusive { x <= end } else { x < end };
+    Ok(match op { MembershipOp::In => contained, MembershipOp::NotIn => !contained })
+}
+
+fn contains(op: &ContainsOp, container: &Value, needle: &Value, collation: Collation) -> Result<bool, EvalError> {
+    if let (Value::String(s), Value::String(sub)) = (container, needle) {
+        let (s, sub) = (collation.fold(s), collation.fold(sub));
+        return Ok(match op {
+            ContainsOp::Contains => s.contains(sub.as_ref()),
+            ContainsOp::NotContains => !s.contains(sub.as_ref()),
+            ContainsOp::StartsWith => s.starts_with(sub.as_ref()),
+            ContainsOp::EndsWith => s.ends_with(sub.as_ref()),
+        });
+    }
+    match (op, container, needle) {
+        (ContainsOp::Contains, Value::Set(items), v) => Ok(items.iter().any(|x| x == v)),
+        (ContainsOp::NotContains, Value::Set(items), v) => Ok(!items.iter().any(|x| x == v)),
+        (ContainsOp::Contains | ContainsOp::NotContains, _, _) => Err(EvalError::Type("contains/not_contains expect string/string or set/T".into())),
+        (ContainsOp::StartsWith | ContainsOp::EndsWith, _, _) => Err(EvalError::Type("starts_with/ends_with expect string/string".into())),
     }
 }
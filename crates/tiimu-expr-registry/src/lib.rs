@@ -6,11 +6,15 @@
 //! - how we record where-used (`ExpressionUsage`),
 //! - the minimal registry trait.
 //!
-//! Concrete storage lives in TIIMU service crates (e.g., Postgres-backed).
+//! Concrete storage lives in TIIMU service crates (e.g., Postgres-backed);
+//! `InMemoryRegistry` is provided for tests and local tooling.
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
 use thiserror::Error;
+use tiimu_expr_ast::Dependencies;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind", rename_all = "snake_case")]
@@ -47,6 +51,104 @@ pub enum RegistryError {
 #[async_trait]
 pub trait ExpressionRegistry: Send + Sync {
     async fn resolve_label(&self, chronicle_id: &str, label: &str) -> Result<String, RegistryError>;
+
+    /// Resolves many `(chronicle_id, label)` pairs, one result per pair in input order.
+    ///
+    /// The outer error is for failures of the batch as a whole; per-label failures (e.g.
+    /// `NotFound`) are reported inline. The default loops over `resolve_label`; storage
+    /// backends should override it with a single round-trip.
+    async fn resolve_labels(&self, refs: &[(String, String)]) -> Result<Vec<Result<String, RegistryError>>, RegistryError> {
+        let mut out = Vec::with_capacity(refs.len());
+        for (chronicle_id, label) in refs {
+            out.push(self.resolve_label(chronicle_id, label).await);
+        }
+        Ok(out)
+    }
+
     async fn record_usage(&self, usage: ExpressionUsage) -> Result<(), RegistryError>;
     async fn list_usages(&self, expression_version_id_or_chronicle: &str) -> Result<Vec<ExpressionUsage>, RegistryError>;
+
+    /// Expression version ids whose dependencies include `field` (dotted, as in
+    /// `Dependencies::fields`), sorted. Backed by a dependency index kept by the storage layer.
+    async fn find_by_field(&self, field: &str) -> Result<Vec<String>, RegistryError>;
+}
+
+/// `HashMap`-backed `ExpressionRegistry` for tests and local tooling.
+///
+/// Labels are seeded with `set_label`, dependencies with `register_dependencies`;
+/// usages are kept in insertion order.
+#[derive(Debug, Default)]
+pub struct InMemoryRegistry {
+    labels: RwLock<HashMap<(String, String), String>>,
+    usages: RwLock<Vec<ExpressionUsage>>,
+    /// Field dependencies per expression version id.
+    fields: RwLock<HashMap<String, HashSet<String>>>,
+}
+
+impl InMemoryRegistry {
+    pub fn new() -> Self { Self::default() }
+
+    /// Points `chronicle_id`/`label` at `expression_version_id`, replacing any previous target.
+    pub fn set_label(&self, chronicle_id: &str, label: &str, expression_version_id: &str) -> Result<(), RegistryError> {
+        self.labels
+            .write()
+            .map_err(|_| poisoned())?
+            .insert((chronicle_id.to_string(), label.to_string()), expression_version_id.to_string());
+        Ok(())
+    }
+
+    /// Indexes the fields of `expression_version_id` for `find_by_field`, replacing any previous entry.
+    pub fn register_dependencies(&self, expression_version_id: &str, deps: &Dependencies) -> Result<(), RegistryError> {
+        self.fields
+            .write()
+            .map_err(|_| poisoned())?
+            .insert(expression_version_id.to_string(), deps.fields.clone());
+        Ok(())
+    }
+}
+
+fn poisoned() -> RegistryError {
+    RegistryError::Storage("in-memory registry lock poisoned".into())
+}
+
+#[async_trait]
+impl ExpressionRegistry for InMemoryRegistry {
+    async fn resolve_label(&self, chronicle_id: &str, label: &str) -> Result<String, RegistryError> {
+        let labels = self.labels.read().map_err(|_| poisoned())?;
+        labels
+            .get(&(chronicle_id.to_string(), label.to_string()))
+            .cloned()
+            .ok_or(RegistryError::NotFound)
+    }
+
+    /// Resolves the whole batch under one read lock.
+    async fn resolve_labels(&self, refs: &[(String, String)]) -> Result<Vec<Result<String, RegistryError>>, RegistryError> {
+        let labels = self.labels.read().map_err(|_| poisoned())?;
+        Ok(refs.iter().map(|key| labels.get(key).cloned().ok_or(RegistryError::NotFound)).collect())
+    }
+
+    async fn record_usage(&self, usage: ExpressionUsage) -> Result<(), RegistryError> {
+        self.usages.write().map_err(|_| poisoned())?.push(usage);
+        Ok(())
+    }
+
+    /// Matches `Pinned` usages by version id and `ByLabel` usages by chronicle id.
+    async fn list_usages(&self, expression_version_id_or_chronicle: &str) -> Result<Vec<ExpressionUsage>, RegistryError> {
+        let usages = self.usages.read().map_err(|_| poisoned())?;
+        Ok(usages
+            .iter()
+            .filter(|u| match &u.expression_ref {
+                ExpressionRef::Pinned { expression_version_id } => expression_version_id == expression_version_id_or_chronicle,
+                ExpressionRef::ByLabel { expression_chronicle_id, .. } => expression_chronicle_id == expression_version_id_or_chronicle,
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn find_by_field(&self, field: &str) -> Result<Vec<String>, RegistryError> {
+        let index = self.fields.read().map_err(|_| poisoned())?;
+        let mut ids: Vec<String> = index.iter().filter(|(_, fields)| fields.contains(field)).map(|(id, _)| id.clone()).collect();
+        ids.sort();
+        Ok(ids)
+    }
 }
@@ -4,18 +4,26 @@
 //! Validates that:
 //! - referenced fields exist in the dictionary snapshot,
 //! - operators are applied to compatible types,
-//! - regex patterns compile,
+//! - regex patterns compile (within `RegexLimits`; see `typecheck_with_options`),
 //! - function calls match declared signatures,
 //! - the top-level expression returns a boolean (deterministic decisioning).
 //!
-//! Runtime assumes validation has already succeeded.
+//! Runtime assumes validation has already succeeded. `lint` additionally flags well-typed but
+//! suspicious constructs (contradictions, self-comparisons, constant conditions, redundant
+//! operands) as warnings; `infer_annotated` reports the inferred type of every sub-expression.
 
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
 use thiserror::Error;
-use tiimu_expr_ast::{CompareOp, Expr, FieldRef, Literal, LiteralOrField};
+use tiimu_expr_ast::{ArithOp, CompareOp, ContainsOp, Expr, FieldRef, Literal, LiteralOrField, LogicalOp};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Ty { Bool, Number, String, Null, Set(Box<Ty>), Any }
+pub enum Ty {
+    Bool, Number, String, Null, Set(Box<Ty>), Any,
+    /// A string restricted to `variants`. Literals compared against (or listed as membership
+    /// targets of) an enum field must be variants; otherwise it behaves as `String`.
+    Enum { name: String, variants: Vec<String> },
+}
 
 pub trait Dictionary {
     fn field_type(&self, field: &FieldRef) -> Option<Ty>;
@@ -25,6 +33,42 @@ pub trait FunctionRegistry {
     fn function_signature(&self, name: &str) -> Option<(Vec<Ty>, Ty)>;
 }
 
+/// `Dictionary` over a map from dotted field path (`customer.tags`) to type.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapDictionary(pub HashMap<String, Ty>);
+
+impl MapDictionary {
+    pub fn new() -> Self { Self::default() }
+
+    /// Declares `path` (dotted) as `ty`, replacing any previous type.
+    pub fn insert(&mut self, path: impl Into<String>, ty: Ty) -> &mut Self {
+        self.0.insert(path.into(), ty);
+        self
+    }
+}
+
+impl Dictionary for MapDictionary {
+    fn field_type(&self, field: &FieldRef) -> Option<Ty> { self.0.get(&*field.dotted()).cloned() }
+}
+
+/// `FunctionRegistry` over a map from function name to `(params, return type)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MapFunctionRegistry(pub HashMap<String, (Vec<Ty>, Ty)>);
+
+impl MapFunctionRegistry {
+    pub fn new() -> Self { Self::default() }
+
+    /// Declares `name(params..) -> ret`, replacing any previous signature.
+    pub fn insert(&mut self, name: impl Into<String>, params: Vec<Ty>, ret: Ty) -> &mut Self {
+        self.0.insert(name.into(), (params, ret));
+        self
+    }
+}
+
+impl FunctionRegistry for MapFunctionRegistry {
+    fn function_signature(&self, name: &str) -> Option<(Vec<Ty>, Ty)> { self.0.get(name).cloned() }
+}
+
 #[derive(Debug, Error)]
 pub enum TypeError {
     #[error("unknown field: {0}")]
@@ -37,104 +81,436 @@ pub enum TypeError {
     InvalidRegex(String),
     #[error("expression must evaluate to boolean")]
     NotBoolean,
+    #[error("list has {len} elements, more than the limit of {max}")]
+    ListTooLong { len: usize, max: usize },
+}
+
+/// A dependency missing from the dictionary or function registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    UnknownField(String),
+    UnknownFunction(String),
+}
+
+/// Functions the typechecker handles itself, so they need no registry entry.
+const SPECIAL_FORMS: &[&str] = &["exists", "coalesce", "contains_any", "contains_all"];
+
+/// Checks every referenced field and function against `dict` / `fns`.
+///
+/// Unlike `typecheck`, which stops at the first error, this reports all unknown fields
+/// (sorted) followed by all unknown functions (sorted).
+pub fn validate_dependencies(expr: &Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry) -> Result<(), Vec<ValidationIssue>> {
+    let deps = tiimu_expr_ast::extract_dependencies(expr);
+    let mut fields: Vec<String> = deps.fields.into_iter()
+        .filter(|f| f.parse::<FieldRef>().map_or(true, |fr| dict.field_type(&fr).is_none()))
+        .collect();
+    fields.sort();
+    let mut functions: Vec<String> = deps.functions.into_iter()
+        .filter(|f| !SPECIAL_FORMS.contains(&f.as_str()) && fns.function_signature(f).is_none())
+        .collect();
+    functions.sort();
+    let issues: Vec<ValidationIssue> = fields.into_iter().map(ValidationIssue::UnknownField)
+        .chain(functions.into_iter().map(ValidationIssue::UnknownFunction))
+        .collect();
+    if issues.is_empty() { Ok(()) } else { Err(issues) }
+}
+
+/// Compiled-size limits for regex patterns, guarding against patterns that compile to huge
+/// programs (e.g. `\w{1000}` under Unicode). See `regex::RegexBuilder::size_limit` and
+/// `dfa_size_limit`; the defaults match the `regex` crate's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegexLimits {
+    pub size_limit: usize,
+    pub dfa_size_limit: usize,
+}
+
+impl Default for RegexLimits {
+    fn default() -> Self { Self { size_limit: 10 * (1 << 20), dfa_size_limit: 2 * (1 << 20) } }
+}
+
+impl RegexLimits {
+    /// Compiles `pattern`, failing if it is invalid or exceeds `size_limit`.
+    pub fn compile(&self, pattern: &str) -> Result<Regex, regex::Error> {
+        RegexBuilder::new(pattern).size_limit(self.size_limit).dfa_size_limit(self.dfa_size_limit).build()
+    }
+}
+
+/// Knobs for `typecheck_with_options`. `Default` matches `typecheck`.
+#[derive(Debug, Clone, Default)]
+pub struct TypeCheckOptions {
+    pub regex_limits: RegexLimits,
+    /// Longest list literal accepted (nested lists are checked individually); `None` for no limit.
+    pub max_list_len: Option<usize>,
 }
 
 pub fn typecheck(expr: &Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry) -> Result<Ty, TypeError> {
-    let ty = infer(expr, dict, fns)?;
+    typecheck_with_options(expr, dict, fns, &TypeCheckOptions::default())
+}
+
+pub fn typecheck_with_options(expr: &Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry, opts: &TypeCheckOptions) -> Result<Ty, TypeError> {
+    let ty = infer(expr, dict, fns, opts, &mut None)?;
     if ty != Ty::Bool { return Err(TypeError::NotBoolean); }
     Ok(ty)
 }
 
-fn infer(expr: &Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry) -> Result<Ty, TypeError> {
-    use tiimu_expr_ast::{LogicalOp, MembershipOp};
+/// An expression node with its inferred type; `children` are the annotated sub-expressions
+/// (operands, call arguments, `?:` parts) in source order. Predicate nodes such as `x > 1`
+/// have no children: their fields and values are not `Expr`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedExpr<'e> {
+    pub expr: &'e Expr,
+    pub ty: Ty,
+    pub children: Vec<TypedExpr<'e>>,
+}
+
+/// Infers the type of `expr` and of every sub-expression, e.g. for hover types in an editor.
+///
+/// Unlike `typecheck`, the root may have any type.
+pub fn infer_annotated<'e>(expr: &'e Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry) -> Result<TypedExpr<'e>, TypeError> {
+    let mut ann = Some(vec![Vec::new()]);
+    infer(expr, dict, fns, &TypeCheckOptions::default(), &mut ann)?;
+    Ok(ann.and_then(|mut stack| stack.pop()).and_then(|mut root| root.pop()).expect("root is annotated"))
+}
+
+/// Stack of child lists for the nodes currently being inferred; `None` when not annotating.
+type Annotations<'e> = Option<Vec<Vec<TypedExpr<'e>>>>;
+
+fn infer<'e>(expr: &'e Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry, opts: &TypeCheckOptions, ann: &mut Annotations<'e>) -> Result<Ty, TypeError> {
+    let Some(stack) = ann else { return infer_node(expr, dict, fns, opts, ann) };
+    stack.push(Vec::new());
+    let ty = infer_node(expr, dict, fns, opts, ann);
+    let stack = ann.as_mut().expect("annotation stack");
+    let children = stack.pop().unwrap_or_default();
+    let ty = ty?;
+    stack.last_mut().expect("parent list").push(TypedExpr { expr, ty: ty.clone(), children });
+    Ok(ty)
+}
+
+fn infer_node<'e>(expr: &'e Expr, dict: &dyn Dictionary, fns: &dyn FunctionRegistry, opts: &TypeCheckOptions, ann: &mut Annotations<'e>) -> Result<Ty, TypeError> {
     match expr {
-        Expr::Not(e) => { ensure_bool(infer(e, dict, fns)?, "! expects bool")?; Ok(Ty::Bool) }
+        Expr::Not(e) => { ensure_bool(infer(e, dict, fns, opts, ann)?, "! expects bool")?; Ok(Ty::Bool) }
         Expr::Logical{lhs, rhs, ..} => {
-            ensure_bool(infer(lhs, dict, fns)?, "lhs must be bool")?;
-            ensure_bool(infer(rhs, dict, fns)?, "rhs must be bool")?;
+            ensure_bool(infer(lhs, dict, fns, opts, ann)?, "lhs must be bool")?;
+            ensure_bool(infer(rhs, dict, fns, opts, ann)?, "rhs must be bool")?;
             Ok(Ty::Bool)
         }
         Expr::Compare{field, op, value} => {
-            let ft = dict.field_type(field).ok_or_else(|| TypeError::UnknownField(field.as_dotted()))?;
-            let vt = infer_value(value, dict)?;
-            match (&ft, &vt) {
-                (Ty::Number, Ty::Number) | (Ty::String, Ty::String) | (Ty::Bool, Ty::Bool) => Ok(Ty::Bool),
-                (t, Ty::Null) | (Ty::Null, t) => match op {
-                    CompareOp::Eq | CompareOp::Ne => Ok(Ty::Bool),
-                    _ => Err(TypeError::TypeMismatch("null only with == or !=".into())),
-                },
-                _ => Err(TypeError::TypeMismatch(format!("cannot compare {:?} with {:?}", ft, vt))),
+            let declared = declared_field_type(dict, field)?;
+            if let LiteralOrField::Lit(Literal::String(s)) = value { check_variant(&declared, s)?; }
+            let ft = erase_enum(declared);
+            let vt = infer_value(value, dict, opts)?;
+            compare_types(op, &ft, &vt)
+        }
+        Expr::CompareExpr{op, lhs, rhs} => {
+            let lt = infer(lhs, dict, fns, opts, ann)?;
+            let rt = infer(rhs, dict, fns, opts, ann)?;
+            compare_types(op, &lt, &rt)
+        }
+        Expr::Arithmetic{op, lhs, rhs} => {
+            let lt = infer(lhs, dict, fns, opts, ann)?;
+            let rt = infer(rhs, dict, fns, opts, ann)?;
+            match (&lt, &rt) {
+                (Ty::Number, Ty::Number) => Ok(Ty::Number),
+                (Ty::String, Ty::String) if *op == ArithOp::Add => Ok(Ty::String),
+                (Ty::String, Ty::String) => Err(TypeError::TypeMismatch("strings support only `+` (concatenation)".into())),
+                _ => Err(TypeError::TypeMismatch(format!("arithmetic expects numbers (or two strings for `+`), got {:?} and {:?}", lt, rt))),
             }
         }
         Expr::Membership{field, op: _op, list} => {
-            let ft = dict.field_type(field).ok_or_else(|| TypeError::UnknownField(field.as_dotted()))?;
-            match (&ft, list) {
-                (Ty::String, LiteralOrField::Lit(Literal::List(_))) => Ok(Ty::Bool),
-                (Ty::Number, LiteralOrField::Lit(Literal::List(_))) => Ok(Ty::Bool),
-                (Ty::Set(_), LiteralOrField::Lit(Literal::List(_))) => Ok(Ty::Bool),
-                (Ty::String, LiteralOrField::Field(fr)) => {
-                    match dict.field_type(fr).ok_or_else(|| TypeError::UnknownField(fr.as_dotted()))? {
-                        Ty::Set(inner) if *inner == Ty::String => Ok(Ty::Bool),
-                        _ => Err(TypeError::TypeMismatch("membership expects set<string>".into())),
-                    }
+            let declared = declared_field_type(dict, field)?;
+            if let LiteralOrField::Lit(Literal::List(items)) = list {
+                for item in items {
+                    if let LiteralOrField::Lit(Literal::String(s)) = item { check_variant(&declared, s)?; }
                 }
-                _ => Err(TypeError::TypeMismatch("invalid membership usage".into())),
+            }
+            let ft = erase_enum(declared);
+            match infer_value(list, dict, opts)? {
+                Ty::Set(inner) if *inner == ft || *inner == Ty::Any => Ok(Ty::Bool),
+                Ty::Set(inner) => Err(TypeError::TypeMismatch(format!("membership of {:?} in set<{:?}>", ft, inner))),
+                Ty::String => Err(TypeError::TypeMismatch(format!("membership target {} is a string, not a set; use `contains` for substrings", describe_value(list)))),
+                _ => Err(TypeError::TypeMismatch(format!("membership target {} must be a list or set field", describe_value(list)))),
             }
         }
-        Expr::Contains{field, value, ..} => {
-            let ft = dict.field_type(field).ok_or_else(|| TypeError::UnknownField(field.as_dotted()))?;
-            let vt = infer_value(value, dict)?;
+        Expr::Between{field, low, high, ..} => {
+            let ft = field_type(dict, field)?;
+            let lt = infer_value(low, dict, opts)?;
+            let ht = infer_value(high, dict, opts)?;
             match ft {
-                Ty::String if vt == Ty::String => Ok(Ty::Bool),
-                Ty::Set(inner) if *inner == vt => Ok(Ty::Bool),
-                _ => Err(TypeError::TypeMismatch("contains requires string/string or set<T>/T".into())),
+                Ty::Number | Ty::String if lt == ft && ht == ft => Ok(Ty::Bool),
+                _ => Err(TypeError::TypeMismatch(format!("between requires number or string bounds matching the field, got {:?} between {:?} and {:?}", ft, lt, ht))),
+            }
+        }
+        Expr::Contains{field, op, value} => {
+            let ft = field_type(dict, field)?;
+            let vt = infer_value(value, dict, opts)?;
+            match (op, ft) {
+                (_, Ty::String) if vt == Ty::String => Ok(Ty::Bool),
+                (ContainsOp::Contains | ContainsOp::NotContains, Ty::Set(inner)) if *inner == vt => Ok(Ty::Bool),
+                (ContainsOp::Contains | ContainsOp::NotContains, _) => Err(TypeError::TypeMismatch("contains/not_contains require string/string or set<T>/T".into())),
+                _ => Err(TypeError::TypeMismatch("starts_with/ends_with require string/string".into())),
             }
         }
-        Expr::RegexMatch{field, pattern} => {
-            let ft = dict.field_type(field).ok_or_else(|| TypeError::UnknownField(field.as_dotted()))?;
+        Expr::RegexMatch{field, pattern, ..} => {
+            let ft = field_type(dict, field)?;
             if ft != Ty::String { return Err(TypeError::TypeMismatch("regex needs string field".into())); }
-            Regex::new(pattern).map_err(|e| TypeError::InvalidRegex(e.to_string()))?;
+            opts.regex_limits.compile(pattern).map_err(|e| TypeError::InvalidRegex(e.to_string()))?;
             Ok(Ty::Bool)
         }
+        // `exists(field)` is an evaluator special form: the field must be known, but may be absent at runtime.
+        Expr::Call{name, args} if name == "exists" => match args.as_slice() {
+            [Expr::Field(fr)] => { field_type(dict, fr)?; Ok(Ty::Bool) }
+            _ => Err(TypeError::TypeMismatch("exists expects a single field reference".into())),
+        },
+        Expr::Call{name, args} if name == "coalesce" => infer_coalesce(args, dict, fns, opts, ann),
+        Expr::Call{name, args} if name == "contains_any" || name == "contains_all" => infer_set_pair(name, args, dict, fns, opts, ann),
         Expr::Call{name, args} => {
             let (params, ret) = fns.function_signature(name).ok_or_else(|| TypeError::UnknownFunction(name.clone()))?;
             if params.len() != args.len() { return Err(TypeError::TypeMismatch("arg count mismatch".into())); }
             for (a, p) in args.iter().zip(params.iter()) {
-                let at = infer(a, dict, fns)?;
+                let at = infer(a, dict, fns, opts, ann)?;
                 if *p != Ty::Any && at != *p { return Err(TypeError::TypeMismatch("arg type mismatch".into())); }
             }
             Ok(ret)
         }
-        Expr::Literal(l) => Ok(match l {
-            Literal::Bool(_) => Ty::Bool,
-            Literal::Number(_) => Ty::Number,
-            Literal::String(_) => Ty::String,
-            Literal::Null => Ty::Null,
-            Literal::Regex(_) => Ty::String,
-            Literal::List(_) => Ty::Any,
-        }),
-        Expr::Field(fr) => dict.field_type(fr).ok_or_else(|| TypeError::UnknownField(fr.as_dotted())),
+        Expr::If{cond, then, els} => {
+            ensure_bool(infer(cond, dict, fns, opts, ann)?, "condition must be bool")?;
+            match (infer(then, dict, fns, opts, ann)?, infer(els, dict, fns, opts, ann)?) {
+                (Ty::Null, t) | (t, Ty::Null) => Ok(t),
+                (Ty::Any, t) | (t, Ty::Any) => Ok(t),
+                (tt, et) if tt == et => Ok(tt),
+                (tt, et) => Err(TypeError::TypeMismatch(format!("conditional branches disagree: {:?} and {:?}", tt, et))),
+            }
+        }
+        Expr::Literal(l) => literal_type(l, dict, opts),
+        Expr::Field(fr) => field_type(dict, fr),
+    }
+}
+
+fn infer_value(v: &LiteralOrField, dict: &dyn Dictionary, opts: &TypeCheckOptions) -> Result<Ty, TypeError> {
+    match v {
+        LiteralOrField::Lit(l) => literal_type(l, dict, opts),
+        LiteralOrField::Field(fr) => field_type(dict, fr),
     }
 }
 
-fn infer_value(v: &LiteralOrField, dict: &dyn Dictionary) -> Result<Ty, TypeError> {
+/// The field's dotted path, or `literal`, for error messages.
+fn describe_value(v: &LiteralOrField) -> String {
     match v {
-        LiteralOrField::Lit(l) => Ok(match l {
-            Literal::Bool(_) => Ty::Bool,
-            Literal::Number(_) => Ty::Number,
-            Literal::String(_) => Ty::String,
-            Literal::Null => Ty::Null,
-            Literal::Regex(_) => Ty::String,
-            Literal::List(_) => Ty::Any,
-        }),
-        LiteralOrField::Field(fr) => dict.field_type(fr).ok_or_else(|| TypeError::UnknownField(fr.as_dotted())),
+        LiteralOrField::Lit(_) => "literal".into(),
+        LiteralOrField::Field(fr) => fr.as_dotted(),
     }
 }
 
+/// `coalesce(a, b)` has the type of its first non-null argument; both must agree when non-null.
+fn infer_coalesce<'e>(args: &'e [Expr], dict: &dyn Dictionary, fns: &dyn FunctionRegistry, opts: &TypeCheckOptions, ann: &mut Annotations<'e>) -> Result<Ty, TypeError> {
+    let [a, b] = args else { return Err(TypeError::TypeMismatch("coalesce expects 2 args".into())) };
+    match (infer(a, dict, fns, opts, ann)?, infer(b, dict, fns, opts, ann)?) {
+        (Ty::Null, t) | (t, Ty::Null) => Ok(t),
+        (Ty::Any, t) | (t, Ty::Any) => Ok(t),
+        (at, bt) if at == bt => Ok(at),
+        (at, bt) => Err(TypeError::TypeMismatch(format!("coalesce arguments disagree: {:?} and {:?}", at, bt))),
+    }
+}
+
+/// `contains_any(set, list)` / `contains_all(set, list)`: two sets of the same element type.
+fn infer_set_pair<'e>(name: &str, args: &'e [Expr], dict: &dyn Dictionary, fns: &dyn FunctionRegistry, opts: &TypeCheckOptions, ann: &mut Annotations<'e>) -> Result<Ty, TypeError> {
+    let [a, b] = args else { return Err(TypeError::TypeMismatch(format!("{} expects 2 args", name))) };
+    match (infer(a, dict, fns, opts, ann)?, infer(b, dict, fns, opts, ann)?) {
+        (Ty::Set(x), Ty::Set(y)) if x == y || *x == Ty::Any || *y == Ty::Any => Ok(Ty::Bool),
+        (at, bt) => Err(TypeError::TypeMismatch(format!("{} expects sets of the same element type, got {:?} and {:?}", name, at, bt))),
+    }
+}
+
+fn literal_type(l: &Literal, dict: &dyn Dictionary, opts: &TypeCheckOptions) -> Result<Ty, TypeError> {
+    Ok(match l {
+        Literal::Bool(_) => Ty::Bool,
+        Literal::Number(_) => Ty::Number,
+        Literal::String(_) => Ty::String,
+        Literal::Null => Ty::Null,
+        Literal::Regex(_) => Ty::String,
+        Literal::List(items) => list_type(items, dict, opts)?,
+        Literal::Range { .. } => Ty::Set(Box::new(Ty::Number)),
+    })
+}
+
+/// `Set<T>` where every element has type `T`; an empty list is `Set<Any>`.
+fn list_type(items: &[LiteralOrField], dict: &dyn Dictionary, opts: &TypeCheckOptions) -> Result<Ty, TypeError> {
+    if let Some(max) = opts.max_list_len.filter(|max| items.len() > *max) {
+        return Err(TypeError::ListTooLong { len: items.len(), max });
+    }
+    let mut elem = Ty::Any;
+    for item in items {
+        let t = infer_value(item, dict, opts)?;
+        elem = match (elem, t) {
+            (Ty::Any, t) => t,
+            (e, Ty::Any) => e,
+            (e, t) if e == t => e,
+            (e, t) => return Err(TypeError::TypeMismatch(format!("mixed list elements: {:?} and {:?}", e, t))),
+        };
+    }
+    Ok(Ty::Set(Box::new(elem)))
+}
+
+fn compare_types(op: &CompareOp, lt: &Ty, rt: &Ty) -> Result<Ty, TypeError> {
+    match (lt, rt) {
+        (Ty::Number, Ty::Number) | (Ty::String, Ty::String) | (Ty::Bool, Ty::Bool) => Ok(Ty::Bool),
+        (Ty::Set(a), Ty::Set(b)) if a == b || **a == Ty::Any || **b == Ty::Any => match op {
+            CompareOp::Eq | CompareOp::Ne => Ok(Ty::Bool),
+            _ => Err(TypeError::TypeMismatch("sets only with == or !=".into())),
+        },
+        (_, Ty::Null) | (Ty::Null, _) => match op {
+            CompareOp::Eq | CompareOp::Ne => Ok(Ty::Bool),
+            _ => Err(TypeError::TypeMismatch("null only with == or !=".into())),
+        },
+        // `x != [1, 2]` is usually meant as membership; the parser cannot tell it apart from set
+        // equality (`tags == ["a", "b"]`), so the hint is given here where types are known.
+        (Ty::Set(_), other) | (other, Ty::Set(_)) if !matches!(other, Ty::Set(_) | Ty::Any) && matches!(op, CompareOp::Eq | CompareOp::Ne) => {
+            let suggestion = if *op == CompareOp::Ne { "not in" } else { "in" };
+            Err(TypeError::TypeMismatch(format!("cannot compare {:?} with {:?}; use `{}` to test membership in a list", lt, rt, suggestion)))
+        }
+        _ => Err(TypeError::TypeMismatch(format!("cannot compare {:?} with {:?}", lt, rt))),
+    }
+}
+
+/// Resolves a field's type with enums erased to `String`; see `declared_field_type`.
+fn field_type(dict: &dyn Dictionary, fr: &FieldRef) -> Result<Ty, TypeError> {
+    declared_field_type(dict, fr).map(erase_enum)
+}
+
+/// Replaces `Enum` (also inside `Set`) with `String`.
+fn erase_enum(ty: Ty) -> Ty {
+    match ty {
+        Ty::Enum { .. } => Ty::String,
+        Ty::Set(inner) => Ty::Set(Box::new(erase_enum(*inner))),
+        other => other,
+    }
+}
+
+/// Fails if `ty` is an enum that does not list `value`; other types pass.
+fn check_variant(ty: &Ty, value: &str) -> Result<(), TypeError> {
+    match ty {
+        Ty::Enum { name, variants } if !variants.iter().any(|v| v == value) => {
+            Err(TypeError::TypeMismatch(format!("'{}' not in enum {} [{}]", value, name, variants.join(", "))))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Resolves a field's type as declared, unwrapping one `Set` level per index segment (`tags[0]`).
+fn declared_field_type(dict: &dyn Dictionary, fr: &FieldRef) -> Result<Ty, TypeError> {
+    let indices = fr.indices();
+    if indices.is_empty() {
+        return dict.field_type(fr).ok_or_else(|| TypeError::UnknownField(fr.as_dotted()));
+    }
+    let base = fr.base();
+    let mut ty = dict.field_type(&base).ok_or_else(|| TypeError::UnknownField(base.as_dotted()))?;
+    for _ in indices {
+        ty = match ty {
+            Ty::Set(inner) => *inner,
+            Ty::Any => Ty::Any,
+            other => return Err(TypeError::TypeMismatch(format!("cannot index into {:?} at {}", other, fr.as_dotted()))),
+        };
+    }
+    Ok(ty)
+}
+
 fn ensure_bool(t: Ty, msg: &str) -> Result<(), TypeError> {
     if t != Ty::Bool { Err(TypeError::TypeMismatch(msg.into())) } else { Ok(()) }
 }
 
+/// A suspicious but well-typed construct reported by `lint`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lint {
+    /// An `&&` chain requires `field` to equal two different literals (`x == 1 && x == 2`).
+    Contradiction { field: String },
+    /// `field` is compared with itself (`x == x`).
+    SelfComparison { field: String },
+    /// A node whose value does not depend on its inputs: `!true`, `x || true`, `true ? a : b`.
+    ConstantCondition(Expr),
+    /// A chain with an operand that never changes its value: `x && true`, `x || false`,
+    /// `x ^^ false`.
+    RedundantOperand(Expr),
+}
+
+/// Warnings for always-true / always-false constructs. Unlike `typecheck`, nothing here is an
+/// error. Fields missing from `dict` are skipped (`typecheck` reports them).
+pub fn lint(expr: &Expr, dict: &dyn Dictionary) -> Vec<Lint> {
+    let mut out = Vec::new();
+    lint_expr(expr, dict, &mut out);
+    out
+}
+
+fn lint_expr(expr: &Expr, dict: &dyn Dictionary, out: &mut Vec<Lint>) {
+    let known = |fr: &FieldRef| dict.field_type(fr).is_some();
+    let is_bool_lit = |e: &Expr| matches!(e, Expr::Literal(Literal::Bool(_)));
+    match expr {
+        Expr::Not(e) => {
+            if is_bool_lit(e) { out.push(Lint::ConstantCondition(expr.clone())); }
+            lint_expr(e, dict, out);
+        }
+        Expr::Logical { op, .. } => {
+            let mut operands = Vec::new();
+            flatten_chain(expr, op, &mut operands);
+            // `false` absorbs an `&&` chain and `true` an `||` chain; the other literal (and
+            // `false` under `^^`) is an identity. `x ^^ true` is `!x`, neither constant nor redundant.
+            let has = |b: bool| operands.iter().any(|e| matches!(e, Expr::Literal(Literal::Bool(v)) if *v == b));
+            let (absorbing, identity) = match op {
+                LogicalOp::And => (Some(false), true),
+                LogicalOp::Or => (Some(true), false),
+                LogicalOp::Xor => (None, false),
+            };
+            if absorbing.is_some_and(has) {
+                out.push(Lint::ConstantCondition(expr.clone()));
+            } else if has(identity) {
+                out.push(Lint::RedundantOperand(expr.clone()));
+            }
+            if *op == LogicalOp::And { lint_contradictions(&operands, &known, out); }
+            for e in operands { lint_expr(e, dict, out); }
+        }
+        Expr::Compare { field, value: LiteralOrField::Field(other), .. } if field == other && known(field) => {
+            out.push(Lint::SelfComparison { field: field.as_dotted() });
+        }
+        Expr::CompareExpr { lhs, rhs, .. } => match (&**lhs, &**rhs) {
+            (Expr::Field(a), Expr::Field(b)) if a == b && known(a) => out.push(Lint::SelfComparison { field: a.as_dotted() }),
+            _ => { lint_expr(lhs, dict, out); lint_expr(rhs, dict, out); }
+        },
+        Expr::Arithmetic { lhs, rhs, .. } => { lint_expr(lhs, dict, out); lint_expr(rhs, dict, out); }
+        Expr::Call { args, .. } => for a in args { lint_expr(a, dict, out); },
+        Expr::If { cond, then, els } => {
+            if is_bool_lit(cond) { out.push(Lint::ConstantCondition(expr.clone())); }
+            lint_expr(cond, dict, out);
+            lint_expr(then, dict, out);
+            lint_expr(els, dict, out);
+        }
+        _ => {}
+    }
+}
+
+fn flatten_chain<'e>(expr: &'e Expr, op: &LogicalOp, out: &mut Vec<&'e Expr>) {
+    match expr {
+        Expr::Logical { op: o, lhs, rhs } if o == op => { flatten_chain(lhs, op, out); flatten_chain(rhs, op, out); }
+        _ => out.push(expr),
+    }
+}
+
+/// Reports each field that the `&&` operands pin to two different literals with `==`.
+fn lint_contradictions(operands: &[&Expr], known: &dyn Fn(&FieldRef) -> bool, out: &mut Vec<Lint>) {
+    let mut pinned: Vec<(&FieldRef, &Literal)> = Vec::new();
+    let mut reported: Vec<&FieldRef> = Vec::new();
+    for e in operands {
+        let Expr::Compare { field, op: CompareOp::Eq, value: LiteralOrField::Lit(lit) } = e else { continue };
+        if !known(field) || reported.contains(&field) { continue; }
+        match pinned.iter().find(|(f, _)| *f == field) {
+            Some((_, prev)) if *prev != lit => {
+                reported.push(field);
+                out.push(Lint::Contradiction { field: field.as_dotted() });
+            }
+            Some(_) => {}
+            None => pinned.push((field, lit)),
+        }
+    }
+}
+
 
 /// Convenience: dependencies for storage/indexing (fields + functions).
 pub fn dependencies(expr: &Expr) -> tiimu_expr_ast::Dependencies {
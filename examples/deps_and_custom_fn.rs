@@ -25,8 +25,8 @@ fn main() {
     let expr = parse_expression(r#"starts_with(signal.entry_source, "so") && customer.is_known == true"#).unwrap();
 
     let deps = extract_dependencies(&expr);
-    println!("fields={:?}", deps.fields);
-    println!("functions={:?}", deps.functions);
+    println!("fields={:?}", deps.sorted_fields());
+    println!("functions={:?}", deps.sorted_functions());
 
     let mut values = HashMap::new();
     values.insert("signal.entry_source".into(), Value::String("social".into()));